@@ -1,68 +1,233 @@
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[allow(clippy::enum_variant_names)]
 pub enum VarType {
     IntType,
     BoolType,
     FloatType,
     StringType,
+    ActorType,
+    ListType(Box<VarType>),
+    MapType(Box<VarType>, Box<VarType>),
+    /// A `record` or `enum` declared at the top level, by name.
+    NamedType(String),
 }
 
-pub type Program = Vec<TopLevelExpr>;
+impl std::fmt::Display for VarType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VarType::IntType => write!(f, "int"),
+            VarType::BoolType => write!(f, "bool"),
+            VarType::FloatType => write!(f, "float"),
+            VarType::StringType => write!(f, "string"),
+            VarType::ActorType => write!(f, "actor"),
+            VarType::ListType(elem) => write!(f, "list<{}>", elem),
+            VarType::MapType(key, value) => write!(f, "map<{}, {}>", key, value),
+            VarType::NamedType(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// A range of byte offsets into the source text a node was parsed from,
+/// along with the id of the file that text belongs to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A node together with the span of source it was parsed from. Derefs to the
+/// node, so code that does not care about locations can ignore the wrapper.
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, file: usize, start: usize, end: usize) -> Self {
+        Spanned { node, span: Span { file, start, end } }
+    }
+}
 
-#[derive(Debug)]
+impl<T> std::ops::Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+pub type Program = Vec<Spanned<TopLevelExpr>>;
+
+#[derive(Debug, Clone)]
 pub enum TopLevelExpr {
-    Actor { actor_name: String, content: Vec<ActorExpr> },
-    Event { event_name: String, params: Vec<VarType> },
-    Func { func_name: String, params: Vec<(String, VarType)>, ret_type: Option<VarType>, body: Vec<ControlFlowExpr> },
+    Import { path: String },
+    Use { module: String, name: String },
+    Actor { actor_name: String, params: Vec<(String, VarType)>, content: Vec<Spanned<ActorExpr>> },
+    /// `event Name(types) priority 5;`, where a higher priority is handled
+    /// sooner. Events declared without one have priority 0.
+    Event { event_name: String, params: Vec<VarType>, priority: i64 },
+    /// `record Name { int a; string b; }`, built with `Name(1, "x")`.
+    Record { record_name: String, fields: Vec<(String, VarType)> },
+    /// `enum Name { A, B, C }`, whose values are written `Name::A`.
+    Enum { enum_name: String, variants: Vec<String> },
+    Func { func_name: String, params: Vec<(String, VarType)>, ret_type: Option<VarType>, body: Vec<Spanned<ControlFlowExpr>> },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ActorExpr {
-    VarDecl { var_name: String, var_type: VarType, initial: Option<ValueExpr> },
-    StateMachine(Vec<StateMachineExpr>),
-    TransitionDecl { event: ValueExpr, conditions: Vec<ValueExpr>, body: Vec<ControlFlowExpr> },
-    EntryDecl(Vec<ControlFlowExpr>),
-    ExitDecl(Vec<ControlFlowExpr>),
+    VarDecl { var_name: String, var_type: VarType, initial: Option<Spanned<ValueExpr>> },
+    StateMachine(Vec<Spanned<StateMachineExpr>>),
+    /// `on Event(x) if ... { }`, or `on Event(x) else { }` when `otherwise`
+    /// is set.
+    TransitionDecl { event: Spanned<ValueExpr>, conditions: Vec<Spanned<ValueExpr>>, otherwise: bool, body: Vec<Spanned<ControlFlowExpr>> },
+    EntryDecl(Vec<Spanned<ControlFlowExpr>>),
+    ExitDecl(Vec<Spanned<ControlFlowExpr>>),
+}
+
+/// How a state activates its sub-states: a sequential state has one active
+/// sub-state at a time, a parallel state has all of its regions active at
+/// once, and a region is a sequential state that is part of a parallel one.
+/// A choice is never active: a transition that reaches it goes on to the
+/// target of its first branch whose conditions hold. Entering a final
+/// state completes the state around it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StateKind {
+    #[default]
+    Sequential,
+    Parallel,
+    Region,
+    Choice,
+    Final,
 }
 
-#[derive(Debug)]
+/// What a composite state remembers when it is exited, so that entering it
+/// again resumes there instead of at `initial`: only its own active
+/// sub-state, or the whole active configuration below it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum History {
+    Shallow,
+    Deep,
+}
+
+/// What a transition exits and enters once its handler has run. An internal
+/// transition (`stay`) exits and enters nothing. An external one (`goto X`)
+/// leaves everything up to the state containing both its own state and
+/// `X`, so `goto` to the handler's own state exits and re-enters it. A
+/// local one (`goto local X`) between a state and a state nested in it
+/// keeps the outer state active and only exits and enters below it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Target {
+    #[default]
+    Internal,
+    External(String),
+    Local(String),
+}
+
+impl Target {
+    /// The state the transition goes to, unless it is internal.
+    pub fn state(&self) -> Option<&str> {
+        match self {
+            Target::Internal => None,
+            Target::External(name) | Target::Local(name) => Some(name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum StateMachineExpr {
-    VarDecl { var_name: String, var_type: VarType, initial: Option<ValueExpr> },
+    VarDecl { var_name: String, var_type: VarType, initial: Option<Spanned<ValueExpr>> },
     InitialStateDecl(String),
-    StateDecl { state_name: String, content: Vec<StateMachineExpr> },
-    TransitionDecl { event: ValueExpr, conditions: Vec<ValueExpr>, target: String, body: Vec<ControlFlowExpr> },
-    EntryDecl(Vec<ControlFlowExpr>),
-    ExitDecl(Vec<ControlFlowExpr>),
+    HistoryDecl(History),
+    /// `defer Name;`, which keeps `Name` events waiting in the inbox for as
+    /// long as the state is active.
+    DeferDecl(String),
+    StateDecl { state_name: String, kind: StateKind, content: Vec<Spanned<StateMachineExpr>> },
+    TransitionDecl { event: Spanned<ValueExpr>, conditions: Vec<Spanned<ValueExpr>>, otherwise: bool, target: Target, body: Vec<Spanned<ControlFlowExpr>> },
+    /// `if ... goto X;` inside a `choice`, or `else goto X;` when `otherwise`
+    /// is set.
+    BranchDecl { conditions: Vec<Spanned<ValueExpr>>, otherwise: bool, target: String },
+    /// `on after(500ms) goto X`, taken once the state has been active for
+    /// `delay` milliseconds.
+    TimeoutDecl { delay: u64, conditions: Vec<Spanned<ValueExpr>>, target: Target, body: Vec<Spanned<ControlFlowExpr>> },
+    /// `on done goto X`, taken once the state has reached a final sub-state,
+    /// or every region of a parallel state has.
+    DoneDecl { conditions: Vec<Spanned<ValueExpr>>, target: Target, body: Vec<Spanned<ControlFlowExpr>> },
+    EntryDecl(Vec<Spanned<ControlFlowExpr>>),
+    ExitDecl(Vec<Spanned<ControlFlowExpr>>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ControlFlowExpr {
-    VarDecl { var_name: String, var_type: VarType, initial: Option<ValueExpr> },
-    SendStatement { target_state: String, event: ValueExpr },
-    AssignStatement { var_name: String, val_expr: ValueExpr },
-    FuncCallStatement(ValueExpr),
+    VarDecl { var_name: String, var_type: VarType, initial: Option<Spanned<ValueExpr>> },
+    SendStatement { target: Spanned<ValueExpr>, event: Spanned<ValueExpr>, delay: Option<u64> },
+    /// `target = value;`, where the target is a variable, possibly followed
+    /// by indexes and field accesses such as `orders[0].qty`.
+    AssignStatement { target: Spanned<ValueExpr>, val_expr: Spanned<ValueExpr> },
+    FuncCallStatement(Spanned<ValueExpr>),
+    ReturnStatement(Option<Spanned<ValueExpr>>),
+    /// `terminate;`, which stops the actor running it for good.
+    TerminateStatement,
+    IfStatement { branches: Vec<(Spanned<ValueExpr>, Vec<Spanned<ControlFlowExpr>>)>, otherwise: Option<Vec<Spanned<ControlFlowExpr>>> },
+    WhileStatement { condition: Spanned<ValueExpr>, body: Vec<Spanned<ControlFlowExpr>> },
+    MatchStatement { scrutinee: Spanned<ValueExpr>, arms: Vec<(MatchPattern, Vec<Spanned<ControlFlowExpr>>)> },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum MatchPattern {
+    Int(i64),
+    Str(String),
+    Variant { enum_name: String, variant: String },
+    Wildcard,
+}
+
+#[derive(Debug, Clone)]
 pub enum ValueExpr {
     Bool(bool),
     Int(i64),
     Float(f64),
     Str(String),
     Ident(String),
-    AddExpr { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    SubExpr { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    MulExpr { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    DivExpr { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    NotExpr { v: Box<ValueExpr> },
-    OrExpr  { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    AndExpr { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    XorExpr { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    EqExpr  { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    NeqExpr { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    LeqExpr { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    GeqExpr { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    LtExpr  { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    GtExpr  { l: Box<ValueExpr>, r: Box<ValueExpr> },
-    FuncCallExpr { func_name: String, func_args: Vec<ValueExpr> },
-}
\ No newline at end of file
+    AddExpr { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    SubExpr { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    MulExpr { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    DivExpr { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    NotExpr { v: Box<Spanned<ValueExpr>> },
+    OrExpr  { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    AndExpr { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    XorExpr { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    EqExpr  { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    NeqExpr { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    LeqExpr { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    GeqExpr { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    LtExpr  { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    GtExpr  { l: Box<Spanned<ValueExpr>>, r: Box<Spanned<ValueExpr>> },
+    FuncCallExpr { func_name: String, func_args: Vec<Spanned<ValueExpr>> },
+    SpawnExpr { actor_name: String, args: Vec<Spanned<ValueExpr>> },
+    /// `[1, 2]`, or `list<int>[]` with the element type given.
+    ListExpr { elem: Option<VarType>, items: Vec<Spanned<ValueExpr>> },
+    /// `["a": 1]`, or `map<string, int>[]` with the key and value types given.
+    MapExpr { types: Option<(VarType, VarType)>, entries: Vec<(Spanned<ValueExpr>, Spanned<ValueExpr>)> },
+    IndexExpr { v: Box<Spanned<ValueExpr>>, index: Box<Spanned<ValueExpr>> },
+    FieldExpr { v: Box<Spanned<ValueExpr>>, field: String },
+    /// `Name::A`, a variant of an enum.
+    VariantExpr { enum_name: String, variant: String },
+    /// The instance running the current code.
+    SelfRef,
+    /// The instance that sent the event being handled.
+    Sender,
+}
+
+impl std::fmt::Display for MatchPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatchPattern::Int(i) => write!(f, "{}", i),
+            MatchPattern::Str(s) => write!(f, "\"{}\"", s),
+            MatchPattern::Variant { enum_name, variant } => write!(f, "{}::{}", enum_name, variant),
+            MatchPattern::Wildcard => write!(f, "_"),
+        }
+    }
+}
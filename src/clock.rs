@@ -0,0 +1,69 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/* CLOCKS */
+
+/// Where the engine gets the time from when it arms and fires timers. Times
+/// are milliseconds since the clock was created.
+pub trait Clock: fmt::Debug + Send {
+    fn now(&self) -> u64;
+
+    /// Lets time pass until `time`, which is never earlier than `now`.
+    fn advance_to(&mut self, time: u64);
+}
+
+/// Time that only moves when the engine moves it, jumping straight to the
+/// next timer instead of waiting for it. Runs are fast and repeatable.
+#[derive(Debug)]
+#[derive(Default)]
+pub struct VirtualClock {
+    pub now: u64,
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> u64 {
+        self.now
+    }
+
+    fn advance_to(&mut self, time: u64) {
+        self.now = self.now.max(time);
+    }
+}
+
+/// Wall-clock time, for running a model against the outside world. Waiting
+/// for a timer blocks the calling thread until it is due.
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn advance_to(&mut self, time: u64) {
+        let now = self.now();
+        if time > now {
+            std::thread::sleep(Duration::from_millis(time - now));
+        }
+    }
+}
+
+impl Default for Box<dyn Clock> {
+    fn default() -> Self {
+        Box::new(VirtualClock::default())
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+use crate::ast::Span;
+use crate::runtime::RuntimeError;
+use crate::source::Location;
+use crate::typecheck::Diagnostic;
+
+/// Everything that can go wrong between reading a program and running it.
+#[derive(Debug)]
+pub enum ProteusError {
+    /// The program file could not be read.
+    Io { path: String, error: std::io::Error },
+    /// The program text does not match the grammar.
+    Parse { message: String, location: Location },
+    /// The program refers to states that do not exist or can never be used.
+    Semantic(Vec<Diagnostic>),
+    /// The program uses values of the wrong type.
+    Type(Vec<Diagnostic>),
+    /// The program failed while running.
+    Runtime { error: Box<RuntimeError>, location: Option<Location> },
+}
+
+impl ProteusError {
+    /// Wraps a runtime error, pointing at the code that raised it when the
+    /// error carries a span `locate` can find the source of.
+    pub fn runtime(error: RuntimeError, locate: impl Fn(Span) -> Option<Location>) -> Self {
+        match error {
+            RuntimeError::At(span, error) => ProteusError::Runtime { error, location: locate(span) },
+            error => ProteusError::Runtime { error: Box::new(error), location: None },
+        }
+    }
+}
+
+impl From<RuntimeError> for ProteusError {
+    fn from(error: RuntimeError) -> Self {
+        ProteusError::runtime(error, |_| None)
+    }
+}
+
+impl fmt::Display for ProteusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProteusError::Io { path, error } => write!(f, "error: cannot read `{}`: {}", path, error),
+            ProteusError::Parse { message, location } => write!(f, "error: {}\n{}", message, location),
+            ProteusError::Semantic(diagnostics) | ProteusError::Type(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            ProteusError::Runtime { error, location } => {
+                write!(f, "runtime error: {}", error)?;
+                if let Some(location) = location {
+                    write!(f, "\n{}", location)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProteusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProteusError::Io { error, .. } => Some(error),
+            ProteusError::Runtime { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eval::EvalEngine;
+    use crate::testing::compile;
+    use super::ProteusError;
+
+    #[test]
+    fn missing_files_are_io_errors() {
+        let mut engine = EvalEngine::default();
+        let Err(ProteusError::Io { path, .. }) = engine.load_from_file("tests/missing.pro") else {
+            panic!("expected an io error");
+        };
+
+        assert_eq!(path, "tests/missing.pro");
+    }
+
+    #[test]
+    fn each_stage_reports_its_own_error() {
+        assert!(matches!(compile("actor {"), Err(ProteusError::Parse { .. })));
+        assert!(matches!(compile("actor A { statemachine { initial B; } }"), Err(ProteusError::Semantic(_))));
+        assert!(matches!(compile("func f() { int x = true; }"), Err(ProteusError::Type(_))));
+    }
+}
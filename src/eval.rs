@@ -1,11 +1,17 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
-use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use lockfree::queue::Queue;
 use multimap::MultiMap;
 use serial_int::SerialGenerator;
 use crate::ast::*;
+use crate::error::ProteusError;
+use crate::resolve::{check_duplicates, namespace, resolve_unit};
+use crate::clock::Clock;
+use crate::runtime::{Delayed, Globals};
+use crate::scheduler::Scheduler;
+use crate::source::Source;
+use crate::typecheck::{typecheck_unit, Diagnostic};
 use lazy_static::lazy_static;
 
 lazy_static! {
@@ -14,31 +20,156 @@ lazy_static! {
 }
 
 #[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
 pub enum Value {
     Bool(bool),
     Int(i64),
     Float(f64),
     Str(String),
+    ActorRef(ActorRef),
+    List { elem: VarType, items: Vec<Value> },
+    /// Entries are kept sorted by key, so maps with the same entries
+    /// compare equal and are shown the same however they were built.
+    Map { key: VarType, value: VarType, entries: Vec<(Value, Value)> },
+    /// Field values in the order the record declares them.
+    Record { name: String, fields: Vec<(String, Value)> },
+    Enum { name: String, variant: String },
+}
+
+/// Refers to one running actor instance. The name of the actor it was
+/// created from is kept for display only; references compare by id.
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct ActorRef {
+    pub id: u32,
+    pub name: String,
+}
+
+impl PartialEq for ActorRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl std::fmt::Display for ActorRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}#{}", self.name, self.id)
+    }
+}
+
+impl Value {
+    pub fn var_type(&self) -> VarType {
+        match self {
+            Value::Bool(_) => VarType::BoolType,
+            Value::Int(_) => VarType::IntType,
+            Value::Float(_) => VarType::FloatType,
+            Value::Str(_) => VarType::StringType,
+            Value::ActorRef(_) => VarType::ActorType,
+            Value::List { elem, .. } => VarType::ListType(Box::new(elem.clone())),
+            Value::Map { key, value, .. } => VarType::MapType(Box::new(key.clone()), Box::new(value.clone())),
+            Value::Record { name, .. } | Value::Enum { name, .. } => VarType::NamedType(name.clone()),
+        }
+    }
+
+    /// Converts a value for storage in a variable of type `typ`, promoting
+    /// ints to floats where a float is expected, including the elements of
+    /// lists and maps.
+    pub fn coerce(self, typ: &VarType) -> Value {
+        match (self, typ) {
+            (Value::Int(i), VarType::FloatType) => Value::Float(i as f64),
+            (Value::List { items, .. }, VarType::ListType(elem)) => Value::List {
+                elem: *elem.clone(),
+                items: items.into_iter().map(|item| item.coerce(elem)).collect(),
+            },
+            (Value::Map { entries, .. }, VarType::MapType(key, value)) => Value::Map {
+                key: *key.clone(),
+                value: *value.clone(),
+                entries: entries.into_iter().map(|(k, v)| (k.coerce(key), v.coerce(value))).collect(),
+            },
+            (val, _) => val,
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::ActorRef(actor) => write!(f, "{}", actor),
+            Value::List { items, .. } => {
+                let items: Vec<_> = items.iter().map(|item| item.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Map { entries, .. } if entries.is_empty() => write!(f, "[:]"),
+            Value::Map { entries, .. } => {
+                let entries: Vec<_> = entries.iter().map(|(k, v)| format!("{}: {}", k, v)).collect();
+                write!(f, "[{}]", entries.join(", "))
+            }
+            Value::Record { name, fields } => {
+                let fields: Vec<_> = fields.iter().map(|(field, v)| format!("{}: {}", field, v)).collect();
+                write!(f, "{}({})", name, fields.join(", "))
+            }
+            Value::Enum { name, variant } => write!(f, "{}::{}", name, variant),
+        }
+    }
 }
 
 #[derive(Debug)]
 #[derive(Default)]
+#[derive(Clone)]
 pub struct EventSignature {
     pub name: String,
     pub params: Vec<VarType>,
+    /// Waiting events with a higher priority are handled first.
+    pub priority: i64,
+    pub span: Span,
 }
 
 impl EventSignature {
-    fn new(name: String, params: Vec<VarType>) -> EventSignature {
-        EventSignature { name, params }
+    fn new(name: String, params: Vec<VarType>, priority: i64, span: Span) -> EventSignature {
+        EventSignature { name, params, priority, span }
+    }
+}
+
+/// A `record` declaration: the fields its values carry, in the order
+/// they are given when one is built.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+pub struct RecordSignature {
+    pub name: String,
+    pub fields: Vec<(String, VarType)>,
+    pub span: Span,
+}
+
+impl RecordSignature {
+    pub fn field(&self, name: &str) -> Option<&VarType> {
+        self.fields.iter().find(|(field, _)| field == name).map(|(_, typ)| typ)
     }
 }
 
+/// An `enum` declaration and its variants, in the order they were declared.
 #[derive(Debug)]
 #[derive(Default)]
+#[derive(Clone)]
+pub struct EnumSignature {
+    pub name: String,
+    pub variants: Vec<String>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
 pub struct EventInstance {
     pub signature: EventSignature,
     pub params: Vec<Value>,
+    /// The instance whose handler sent the event, if it came from one.
+    pub sender: Option<ActorRef>,
 }
 
 pub trait Inbox {
@@ -48,7 +179,7 @@ pub trait Inbox {
 
 pub trait Environment {
     fn set_var(&mut self, name: String, typ: VarType, initial: Value);
-    fn get_var(&mut self, name: String) -> Option<&(VarType, Value)>;
+    fn get_var(&self, name: &str) -> Option<&(VarType, Value)>;
 }
 
 /* FUNCTIONS */
@@ -59,36 +190,115 @@ pub struct FuncSignature {
     pub func_name: String,
     pub params: Vec<(String, VarType)>,
     pub ret_type: Option<VarType>,
-    pub body: Vec<ControlFlowExpr>,
+    pub body: Vec<Spanned<ControlFlowExpr>>,
+    pub span: Span,
 }
 
 impl FuncSignature {
-    pub fn new(func_name: String, params: Vec<(String, VarType)>, ret_type: Option<VarType>, body: Vec<ControlFlowExpr>) -> Self {
+    pub fn new(func_name: String, params: Vec<(String, VarType)>, ret_type: Option<VarType>, body: Vec<Spanned<ControlFlowExpr>>, span: Span) -> Self {
         FuncSignature {
             func_name,
             params,
             ret_type,
-            body
+            body,
+            span
         }
     }
 }
 
 /* ACTORS */
 
+/// An actor is declared once in a unit and serves as a template; what runs
+/// are instances created from it by `instantiate`, each with its own id,
+/// variables, inbox and active states.
 #[derive(Debug)]
 #[derive(Default)]
 pub struct Actor {
     pub id: u32,
     pub name: String,
+    pub params: Vec<(String, VarType)>,
     pub queue: Queue<EventInstance>,
+    /// Events taken off `queue` that have not been handled yet, oldest
+    /// first: those the active states defer, and those waiting behind an
+    /// event of higher priority.
+    pub waiting: VecDeque<EventInstance>,
+    /// How many times `poll` found only deferred events, each of which
+    /// left an event without its turn to be handled.
+    pub parked: usize,
     pub env: HashMap<String, (VarType, Value)>,
+    pub decls: Vec<(String, VarType, Spanned<ValueExpr>)>,
     pub statemachine: Option<State>,
     pub transitions: MultiMap<String, Transition>,
+    /// Where handlers of this actor and its states name an event parameter
+    /// with something other than an identifier. Those handlers are left out
+    /// of `transitions` and reported when the program is checked.
+    pub malformed: Vec<Span>,
+    /// Set by `terminate`. The engine drops the instance once the handler
+    /// that terminated it returns.
+    pub terminated: bool,
+    pub span: Span,
+}
+
+impl Actor {
+    /// Creates a fresh instance of this actor, with no variables set and
+    /// every state machine back at its declared initial states.
+    pub fn instantiate(&self) -> Actor {
+        Actor {
+            id: ID_GEN.lock().unwrap().generate(),
+            name: self.name.clone(),
+            params: self.params.clone(),
+            queue: Queue::new(),
+            waiting: VecDeque::new(),
+            parked: 0,
+            env: HashMap::new(),
+            decls: self.decls.clone(),
+            statemachine: self.statemachine.clone(),
+            transitions: self.transitions.clone(),
+            malformed: vec![],
+            terminated: false,
+            span: self.span,
+        }
+    }
+
+    pub fn reference(&self) -> ActorRef {
+        ActorRef { id: self.id, name: self.name.clone() }
+    }
+
+    /// Gives back the turns `poll` lost to deferred events, now that the
+    /// active states may have changed, and returns how many there were.
+    pub fn unpark(&mut self) -> usize {
+        std::mem::take(&mut self.parked)
+    }
 }
 
 impl Inbox for Actor {
+    /// Takes the waiting event with the highest priority, the oldest of
+    /// them if there are several, skipping events an active state defers.
     fn poll(&mut self) -> Option<EventInstance> {
-        self.queue.pop()
+        while let Some(event) = self.queue.pop() {
+            self.waiting.push_back(event);
+        }
+
+        let mut deferred = HashSet::new();
+        if let Some(root) = &self.statemachine {
+            root.deferred_events(&mut deferred);
+        }
+
+        let next = self.waiting.iter()
+            .enumerate()
+            .filter(|(_, event)| !deferred.contains(event.signature.name.as_str()))
+            .max_by_key(|(index, event)| (event.signature.priority, std::cmp::Reverse(*index)))
+            .map(|(index, _)| index);
+
+        match next {
+            Some(index) => self.waiting.remove(index),
+            None => {
+                if !self.waiting.is_empty() {
+                    self.parked += 1;
+                }
+                None
+            }
+        }
     }
 
     fn push(&mut self, event: EventInstance) {
@@ -101,64 +311,127 @@ impl Environment for Actor {
         self.env.insert(name, (typ, val));
     }
 
-    fn get_var(&mut self, name: String) -> Option<&(VarType, Value)> {
-        self.env.get(name.as_str())
+    fn get_var(&self, name: &str) -> Option<&(VarType, Value)> {
+        self.env.get(name)
     }
 }
 
 /* STATE */
 
-type ValueThunk = ValueExpr;
-type Block = Vec<ControlFlowExpr>;
+type ValueThunk = Spanned<ValueExpr>;
+type Block = Arc<Vec<Spanned<ControlFlowExpr>>>;
 
 #[derive(Debug)]
 #[derive(Default)]
+#[derive(Clone)]
 pub struct Transition {
     pub event_name: String,
     pub bound_vars: Vec<String>,
     pub conditions: Vec<ValueThunk>,
-    pub target: String,
+    /// Declared with `else`: only taken when no other handler for the
+    /// event declared in the same place is.
+    pub otherwise: bool,
+    pub target: Target,
     pub body: Block,
+    /// For `on after(...)` handlers, how long the state must stay active
+    /// before the transition is taken, in milliseconds.
+    pub after: Option<u64>,
+    pub span: Span,
 }
 
 impl Transition {
-    fn try_eval(func: ValueExpr, conditions: Vec<ValueExpr>, target: String, body: Block) -> Option<Transition> {
-        return if let ValueExpr::FuncCallExpr { func_name, func_args } = func {
-            let mut args = func_args.iter().map(|arg| {
-                if let ValueExpr::Ident(id) = arg {
-                    Some(id.clone())
-                } else {
-                    None
-                }
-            });
-
-            if args.clone().any(|x| x.is_none()) {
-                None
+    /// Builds the handler for an event written like a call, e.g. `on Ev(a, b)`.
+    /// Fails with the span of the first parameter that is not an identifier.
+    fn try_eval(func: Spanned<ValueExpr>, conditions: Vec<Spanned<ValueExpr>>, otherwise: bool, target: Target, body: Vec<Spanned<ControlFlowExpr>>, span: Span) -> Result<Transition, Span> {
+        let ValueExpr::FuncCallExpr { func_name, func_args } = func.node else {
+            return Err(func.span);
+        };
+
+        let bound_vars = func_args.iter().map(|arg| {
+            if let ValueExpr::Ident(id) = &arg.node {
+                Ok(id.clone())
             } else {
-                let arg_names = args.map(|x| x.unwrap().clone()).collect();
-                Some(Transition {
-                    event_name: func_name,
-                    bound_vars: arg_names,
-                    conditions,
-                    target,
-                    body
-                })
+                Err(arg.span)
             }
-        } else {
-            None
-        }
+        }).collect::<Result<Vec<String>, Span>>()?;
+
+        Ok(Transition {
+            event_name: func_name,
+            bound_vars,
+            conditions,
+            target,
+            body: Arc::new(body),
+            after: None,
+            otherwise,
+            span
+        })
     }
 }
 
 #[derive(Debug)]
 #[derive(Default)]
+#[derive(Clone)]
 pub struct State {
     pub id: u32,
     pub name: String,
+    pub kind: StateKind,
     pub at: String,
+    pub initial: String,
+    pub history: Option<History>,
+    /// The sub-state that was active when this state was last exited.
+    pub last: Option<String>,
+    /// How many times the state has been entered, so timers armed on an
+    /// earlier visit can tell they are out of date.
+    pub entered: u64,
     pub env: HashMap<String, (VarType, Value)>,
+    pub decls: Vec<(String, VarType, Spanned<ValueExpr>)>,
     pub subs: HashMap<String, State>,
+    pub order: Vec<String>,
+    /// Sub-states declared under a name already taken here, with where.
+    /// Only the first declaration is kept.
+    pub duplicates: Vec<(String, Span)>,
     pub transitions: MultiMap<String, Transition>,
+    /// Events declared with `defer`, with where each was declared.
+    pub deferred: Vec<(String, Span)>,
+    pub span: Span,
+}
+
+impl State {
+    /// Collects the events deferred by this state and the states active
+    /// below it.
+    pub fn deferred_events<'s>(&'s self, events: &mut HashSet<&'s str>) {
+        events.extend(self.deferred.iter().map(|(name, _)| name.as_str()));
+        for sub in self.active_subs() {
+            self.subs[sub].deferred_events(events);
+        }
+    }
+
+    /// The sub-states active while this state is: all of them for a
+    /// parallel state, otherwise the one selected by `at`, if any.
+    pub fn active_subs(&self) -> Vec<&String> {
+        match self.kind {
+            StateKind::Parallel => self.order.iter().collect(),
+            _ => self.subs.get_key_value(&self.at).map(|(name, _)| name).into_iter().collect(),
+        }
+    }
+
+    /// Finds the nested state called `name` and returns the names of the
+    /// states leading to it, starting with the direct child of `self`.
+    pub fn path_to(&self, name: &str) -> Option<Vec<String>> {
+        for sub_name in &self.order {
+            let sub = &self.subs[sub_name];
+            if sub.name == name {
+                return Some(vec![sub.name.clone()]);
+            }
+
+            if let Some(mut path) = sub.path_to(name) {
+                path.insert(0, sub.name.clone());
+                return Some(path);
+            }
+        }
+
+        None
+    }
 }
 
 impl Environment for State {
@@ -166,57 +439,87 @@ impl Environment for State {
         self.env.insert(name, (typ, val));
     }
 
-    fn get_var(&mut self, name: String) -> Option<&(VarType, Value)> {
-        self.env.get(name.as_str())
+    fn get_var(&self, name: &str) -> Option<&(VarType, Value)> {
+        self.env.get(name)
     }
 }
 
 /* TOP LEVEL */
 
-use lalrpop_util::lalrpop_mod;
-lalrpop_mod!(pub proteus);
+use lalrpop_util::{lalrpop_mod, ParseError};
+use lalrpop_util::lexer::Token;
+lalrpop_mod!(#[allow(clippy::all, unused)] pub proteus);
 
 #[derive(Debug)]
 #[derive(Default)]
 pub struct EvalEngine {
     pub units: MultiMap<String, InterpretationUnit>,
+    pub globals: Globals,
+    pub instances: BTreeMap<u32, Actor>,
+    pub clock: Box<dyn Clock>,
+    /// Pending timers keyed by when they are due, then by when they were
+    /// armed, so timers due at the same time fire in a fixed order.
+    pub(crate) timers: BTreeMap<(u64, u64), Delayed>,
+    pub(crate) timer_seq: u64,
+    pub scheduler: Scheduler,
+    pub search_paths: Vec<PathBuf>,
+    pub(crate) modules: HashSet<PathBuf>,
+    pub(crate) files: usize,
 }
 
 impl EvalEngine {
-    pub fn load_from_file(&mut self, filepath: &str) -> Result<(), String> {
-        let name = filepath.to_string();
-        let mut file = File::open(filepath).expect("Filepath doesn't exist");
-        let mut content = String::new();
-        file.read_to_string(&mut content).expect("Cannot read file to string");
-        match proteus::ProgramParser::new().parse(&content) {
-            Ok(ast) => {
-                let unit = eval_program(name, ast);
-                self.units.insert("ROOT".to_string(), unit);
-                Ok(())
-            }
+    pub(crate) fn next_file_id(&mut self) -> usize {
+        self.files += 1;
+        self.files
+    }
 
-            Err(err) => Err(format!("{:?}", err))
+    pub(crate) fn parse_unit(&mut self, key: &str, source: Source) -> Result<InterpretationUnit, ProteusError> {
+        match proteus::ProgramParser::new().parse(source.id, &source.text) {
+            Ok(ast) => Ok(eval_program(key.to_string(), source, ast)),
+
+            Err(err) => {
+                let (message, span) = describe_parse_error(err, &source.text);
+                Err(ProteusError::Parse { message, location: source.locate(span) })
+            }
         }
     }
 
-    pub fn load_from_string(&mut self, text: &str) -> Result<(), String> {
-        match proteus::ProgramParser::new().parse(text) {
-            Ok(ast) => {
-                let unit = eval_program("".to_string(), ast);
-                self.units.insert("".to_string(), unit);
-                Ok(())
-            }
+    /// Every loaded unit, in the order their files were read.
+    pub fn units_in_order(&self) -> Vec<&InterpretationUnit> {
+        let mut units: Vec<_> = self.units.iter_all().flat_map(|(_, units)| units.iter()).collect();
+        units.sort_by_key(|unit| unit.source.id);
+        units
+    }
 
-            Err(err) => Err(format!("{:?}", err))
+    fn resolve(&mut self) -> Vec<Diagnostic> {
+        let mut diagnostics = check_duplicates(&self.units_in_order());
+        for unit in self.units_in_order() {
+            diagnostics.extend(namespace(unit, &self.units).1);
+            diagnostics.extend(resolve_unit(unit));
         }
+        diagnostics
     }
 
-    fn typecheck(&mut self) -> Result<(), String> {
-        Ok(())
+    fn typecheck(&mut self) -> Vec<Diagnostic> {
+        self.units_in_order().into_iter()
+            .flat_map(|unit| typecheck_unit(unit, &namespace(unit, &self.units).0))
+            .collect()
     }
 
-    pub fn compile(&mut self) -> Result<(), String> {
-        self.typecheck()
+    /// Checks every loaded unit. State machine problems are reported before
+    /// type errors, since the latter are often a consequence of the former.
+    pub fn compile(&mut self) -> Result<(), ProteusError> {
+        let diagnostics = self.resolve();
+        if !diagnostics.is_empty() {
+            return Err(ProteusError::Semantic(diagnostics));
+        }
+
+        let diagnostics = self.typecheck();
+        if !diagnostics.is_empty() {
+            return Err(ProteusError::Type(diagnostics));
+        }
+
+        Ok(())
     }
 }
 
@@ -224,9 +527,70 @@ impl EvalEngine {
 #[derive(Default)]
 pub struct InterpretationUnit {
     pub name: String,
-    pub actors: HashMap<String, Actor>,
+    pub actors: HashMap<String, Arc<Actor>>,
     pub events: HashMap<String, EventSignature>,
-    pub funcs: HashMap<String, FuncSignature>,
+    pub records: HashMap<String, RecordSignature>,
+    pub enums: HashMap<String, EnumSignature>,
+    pub funcs: HashMap<String, Arc<FuncSignature>>,
+    pub imports: Vec<Import>,
+    /// Declarations that reuse a name already declared earlier in the same
+    /// unit, which the maps above only keep the last of.
+    pub redeclared: Vec<(String, Span)>,
+    pub source: Source,
+}
+
+/// An `import "file.pro";` or `use module::Name;` declaration. Once the file
+/// has been loaded, `module` holds the key of its unit.
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Import {
+    pub file: String,
+    pub name: Option<String>,
+    pub module: String,
+    pub span: Span,
+}
+
+/// Names the tokens the grammar matches with a regex, which the parser
+/// would otherwise report by their pattern. The patterns are written here
+/// exactly as LALRPOP reports them for the terminals in `proteus.lalrpop`.
+fn token_name(token: String) -> String {
+    let name = match token.as_str() {
+        r###"r#"[a-zA-Z][_a-zA-Z0-9]*"#"### => "identifier",
+        r###"r#"\"[^\"]*\""#"### => "string",
+        r###"r#"[0-9]+(ms|s|m)"#"### => "duration",
+        r###"r#"[0-9]+\\.[0-9]+"#"### => "float",
+        r###"r#"[0-9]+"#"### => "int",
+        _ => return token,
+    };
+    name.to_string()
+}
+
+/// Turns a parser error into a readable message and the span it points at
+/// in `text`.
+fn describe_parse_error(err: ParseError<usize, Token<'_>, Spanned<&str>>, text: &str) -> (String, Span) {
+    let expected = |expected: Vec<String>| {
+        let expected: Vec<_> = expected.into_iter().map(token_name).collect();
+        match expected.len() {
+            0 => String::new(),
+            1 => format!(", expected {}", expected[0]),
+            _ => format!(", expected one of {}", expected.join(", ")),
+        }
+    };
+
+    match err {
+        ParseError::InvalidToken { location } => {
+            let width = text[location..].chars().next().map_or(0, char::len_utf8);
+            ("invalid token".to_string(), Span { file: 0, start: location, end: location + width })
+        }
+        ParseError::UnrecognizedEOF { location, expected: exp } =>
+            (format!("unexpected end of file{}", expected(exp)), Span { file: 0, start: location, end: location }),
+        ParseError::UnrecognizedToken { token: (start, token, end), expected: exp } =>
+            (format!("unexpected `{}`{}", token.1, expected(exp)), Span { file: 0, start, end }),
+        ParseError::ExtraToken { token: (start, token, end) } =>
+            (format!("unexpected `{}`", token.1), Span { file: 0, start, end }),
+        ParseError::User { error } =>
+            (error.node.to_string(), error.span),
+    }
 }
 
 impl InterpretationUnit {
@@ -235,42 +599,44 @@ impl InterpretationUnit {
             name: name.clone(),
             actors: HashMap::new(),
             events: HashMap::new(),
+            records: HashMap::new(),
+            enums: HashMap::new(),
             funcs: HashMap::new(),
+            imports: vec![],
+            redeclared: vec![],
+            source: Source::default(),
         }
     }
 }
 
-pub fn eval_pure(val: ValueExpr) -> Option<Value> {
-    match val {
-        ValueExpr::Bool(b) => Some(Value::Bool(b)),
-        ValueExpr::Int(i) => Some(Value::Int(i)),
-        ValueExpr::Float(f) => Some(Value::Float(f)),
-        ValueExpr::Str(s) => Some(Value::Str(s)),
-        _ => None
-    }
-}
+pub fn eval_actor(name: String, params: Vec<(String, VarType)>, span: Span, content: Vec<Spanned<ActorExpr>>) -> Actor {
+    let mut actor = Actor {
+        id: ID_GEN.lock().unwrap().generate(),
+        name,
+        params,
+        span,
+        ..Default::default()
+    };
 
-pub fn eval_actor(name: String, content: Vec<ActorExpr>) -> Actor {
-    let mut actor = Actor::default();
-    actor.id = ID_GEN.lock().unwrap().generate();
-    actor.name = name.clone();
     for e in content {
-        match e {
+        let span = e.span;
+        match e.node {
             ActorExpr::VarDecl { var_name, var_type, initial } => {
-                actor.set_var(var_name, var_type, initial.map(|m| eval_pure(m).expect("Expected value, found expression.")).unwrap());
+                if let Some(expr) = initial {
+                    actor.decls.push((var_name, var_type, expr));
+                }
             }
 
             ActorExpr::StateMachine(sm) => {
                 let mut statemachine = Option::Some(State::default());
-                eval_state(&actor.name, &mut statemachine, sm);
+                eval_state(&actor.name, span, &mut statemachine, sm, &mut actor.malformed);
                 actor.statemachine = statemachine;
             }
 
-            ActorExpr::TransitionDecl { event, conditions, body } => {
-                if let Some(trans) = Transition::try_eval(event, conditions, String::default(), body) {
-                    actor.transitions.insert(trans.event_name.clone(), trans);
-                } else {
-                    println!("FAILED TO INTERPRET TRANSITION");
+            ActorExpr::TransitionDecl { event, conditions, otherwise, body } => {
+                match Transition::try_eval(event, conditions, otherwise, Target::Internal, body, span) {
+                    Ok(trans) => actor.transitions.insert(trans.event_name.clone(), trans),
+                    Err(span) => actor.malformed.push(span),
                 }
             }
 
@@ -279,8 +645,11 @@ pub fn eval_actor(name: String, content: Vec<ActorExpr>) -> Actor {
                     event_name: "_ENTRY".to_string(),
                     bound_vars: vec![],
                     conditions: vec![],
-                    target: "".to_string(),
-                    body
+                    target: Target::Internal,
+                    body: Arc::new(body),
+                    after: None,
+                    otherwise: false,
+                    span
                 })
             }
 
@@ -289,8 +658,11 @@ pub fn eval_actor(name: String, content: Vec<ActorExpr>) -> Actor {
                     event_name: "_EXIT".to_string(),
                     bound_vars: vec![],
                     conditions: vec![],
-                    target: "".to_string(),
-                    body
+                    target: Target::Internal,
+                    body: Arc::new(body),
+                    after: None,
+                    otherwise: false,
+                    span
                 })
             }
         }
@@ -299,40 +671,103 @@ pub fn eval_actor(name: String, content: Vec<ActorExpr>) -> Actor {
     actor
 }
 
-pub fn eval_state(name: &str, state: &mut Option<State>, sm: Vec<StateMachineExpr>) {
-    state.as_mut().map(|state| {
+pub fn eval_state(name: &str, span: Span, state: &mut Option<State>, sm: Vec<Spanned<StateMachineExpr>>, malformed: &mut Vec<Span>) {
+    if let Some(state) = state.as_mut() {
         state.id = ID_GEN.lock().unwrap().generate();
         state.name = name.to_string();
+        state.span = span;
 
         for e in sm {
-            match e {
+            let span = e.span;
+            match e.node {
                 StateMachineExpr::VarDecl { var_name, var_type, initial } => {
-                    state.set_var(var_name, var_type, initial.map(|m| eval_pure(m).expect("Expected value, found expression.")).unwrap());
+                    if let Some(expr) = initial {
+                        state.decls.push((var_name, var_type, expr));
+                    }
                 }
 
                 StateMachineExpr::InitialStateDecl(state_name) => {
-                    state.at = state_name;
+                    state.at = state_name.clone();
+                    state.initial = state_name;
+                }
+
+                StateMachineExpr::HistoryDecl(history) => {
+                    state.history = Some(history);
+                }
+
+                StateMachineExpr::DeferDecl(event_name) => {
+                    state.deferred.push((event_name, span));
                 }
 
-                StateMachineExpr::StateDecl { state_name, content } => {
-                    let mut sub = Option::Some(State::default());
-                    eval_state(&state_name, &mut sub, content);
-                    state.subs.insert(state_name, sub.unwrap());
+                StateMachineExpr::StateDecl { state_name, kind, content } => {
+                    let mut sub = Option::Some(State { kind, ..State::default() });
+                    eval_state(&state_name, span, &mut sub, content, malformed);
+                    if let Some(sub) = sub {
+                        if state.subs.contains_key(&state_name) {
+                            state.duplicates.push((state_name, span));
+                        } else {
+                            state.order.push(state_name.clone());
+                            state.subs.insert(state_name, sub);
+                        }
+                    }
                 }
 
-                StateMachineExpr::TransitionDecl { event, conditions, target, body } => {
-                    if let Some(trans) = Transition::try_eval(event, conditions, target, body) {
-                        state.transitions.insert(trans.event_name.clone(), trans);
+                StateMachineExpr::TransitionDecl { event, conditions, otherwise, target, body } => {
+                    match Transition::try_eval(event, conditions, otherwise, target, body, span) {
+                        Ok(trans) => state.transitions.insert(trans.event_name.clone(), trans),
+                        Err(span) => malformed.push(span),
                     }
                 }
 
+                StateMachineExpr::BranchDecl { conditions, otherwise, target } => {
+                    state.transitions.insert(String::from("_CHOICE"), Transition {
+                        event_name: "_CHOICE".to_string(),
+                        bound_vars: vec![],
+                        conditions,
+                        target: Target::External(target),
+                        body: Arc::new(vec![]),
+                        after: None,
+                        otherwise,
+                        span
+                    })
+                }
+
+                StateMachineExpr::TimeoutDecl { delay, conditions, target, body } => {
+                    state.transitions.insert(String::from("_AFTER"), Transition {
+                        event_name: "_AFTER".to_string(),
+                        bound_vars: vec![],
+                        conditions,
+                        target,
+                        body: Arc::new(body),
+                        after: Some(delay),
+                        otherwise: false,
+                        span
+                    })
+                }
+
+                StateMachineExpr::DoneDecl { conditions, target, body } => {
+                    state.transitions.insert(String::from("_DONE"), Transition {
+                        event_name: "_DONE".to_string(),
+                        bound_vars: vec![],
+                        conditions,
+                        target,
+                        body: Arc::new(body),
+                        after: None,
+                        otherwise: false,
+                        span
+                    })
+                }
+
                 StateMachineExpr::EntryDecl(body) => {
                     state.transitions.insert(String::from("_ENTRY"), Transition {
                         event_name: "_ENTRY".to_string(),
                         bound_vars: vec![],
                         conditions: vec![],
-                        target: "".to_string(),
-                        body
+                        target: Target::Internal,
+                        body: Arc::new(body),
+                        after: None,
+                        otherwise: false,
+                        span
                     })
                 }
 
@@ -341,30 +776,64 @@ pub fn eval_state(name: &str, state: &mut Option<State>, sm: Vec<StateMachineExp
                         event_name: "_EXIT".to_string(),
                         bound_vars: vec![],
                         conditions: vec![],
-                        target: "".to_string(),
-                        body
+                        target: Target::Internal,
+                        body: Arc::new(body),
+                        after: None,
+                        otherwise: false,
+                        span
                     })
                 }
             }
         }
-    });
+    }
 }
 
-pub fn eval_program(name: String, program: Program) -> InterpretationUnit {
+pub fn eval_program(name: String, source: Source, program: Program) -> InterpretationUnit {
     let mut unit = InterpretationUnit::new(name);
+    unit.source = source;
+    let mut declared: HashSet<String> = HashSet::new();
 
     for e in program {
-        match e {
-            TopLevelExpr::Actor { actor_name, content } => {
-                unit.actors.insert(actor_name.clone(), eval_actor(actor_name, content));
+        let span = e.span;
+        let name = match &e.node {
+            TopLevelExpr::Actor { actor_name: name, .. }
+            | TopLevelExpr::Event { event_name: name, .. }
+            | TopLevelExpr::Record { record_name: name, .. }
+            | TopLevelExpr::Enum { enum_name: name, .. }
+            | TopLevelExpr::Func { func_name: name, .. } => Some(name),
+            TopLevelExpr::Import { .. } | TopLevelExpr::Use { .. } => None,
+        };
+        if let Some(name) = name.filter(|name| !declared.insert(name.to_string())) {
+            unit.redeclared.push((name.clone(), span));
+        }
+
+        match e.node {
+            TopLevelExpr::Actor { actor_name, params, content } => {
+                unit.actors.insert(actor_name.clone(), Arc::new(eval_actor(actor_name, params, span, content)));
+            }
+
+            TopLevelExpr::Import { path } => {
+                unit.imports.push(Import { file: path, name: None, module: String::new(), span });
+            }
+
+            TopLevelExpr::Use { module, name } => {
+                unit.imports.push(Import { file: format!("{}.pro", module), name: Some(name), module, span });
+            }
+
+            TopLevelExpr::Event { event_name, params, priority } => {
+                unit.events.insert(event_name.clone(), EventSignature::new(event_name, params, priority, span));
+            }
+
+            TopLevelExpr::Record { record_name, fields } => {
+                unit.records.insert(record_name.clone(), RecordSignature { name: record_name, fields, span });
             }
 
-            TopLevelExpr::Event { event_name, params } => {
-                unit.events.insert(event_name.clone(), EventSignature::new(event_name, params));
+            TopLevelExpr::Enum { enum_name, variants } => {
+                unit.enums.insert(enum_name.clone(), EnumSignature { name: enum_name, variants, span });
             }
 
             TopLevelExpr::Func { func_name, params, ret_type, body } => {
-                unit.funcs.insert(func_name.clone(), FuncSignature::new(func_name, params, ret_type, body));
+                unit.funcs.insert(func_name.clone(), Arc::new(FuncSignature::new(func_name, params, ret_type, body, span)));
             }
         }
     }
@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use crate::error::ProteusError;
+use crate::eval::*;
+use crate::runtime::*;
+
+/* WORKER POOL */
+
+/// How long an idle worker waits for a message before checking again
+/// whether the whole pool has gone quiet.
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+/// What workers send each other: an event for one of the receiver's actors,
+/// or a freshly started instance the receiver owns from now on.
+enum Message {
+    Deliver(Envelope),
+    Adopt(Box<Actor>),
+}
+
+/// What every worker of a pool can see. `pending` counts the events and
+/// instances that have been sent but not yet handled or adopted; once it
+/// drops to zero nothing is left to do anywhere.
+#[derive(Default)]
+struct Shared {
+    pending: AtomicUsize,
+    events: AtomicUsize,
+    failed: AtomicBool,
+}
+
+/// One thread of the pool and the instances it owns. An instance only ever
+/// belongs to one worker, which handles its events one at a time.
+struct Worker<'a> {
+    actors: BTreeMap<u32, Actor>,
+    ready: VecDeque<u32>,
+    delayed: Vec<(u64, Delayed)>,
+    inbox: Receiver<Message>,
+    routes: Vec<Sender<Message>>,
+    globals: &'a Globals,
+    shared: &'a Shared,
+}
+
+/// The worker that owns the instance with the given id.
+fn owner(id: u32, workers: usize) -> usize {
+    id as usize % workers
+}
+
+impl Worker<'_> {
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        while !self.shared.failed.load(Ordering::SeqCst) {
+            while let Ok(message) = self.inbox.try_recv() {
+                self.receive(message)?;
+            }
+
+            if let Some(id) = self.ready.pop_front() {
+                self.handle(id)?;
+                continue;
+            }
+
+            if self.shared.pending.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+
+            match self.inbox.recv_timeout(IDLE_WAIT) {
+                Ok(message) => self.receive(message)?,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn receive(&mut self, message: Message) -> Result<(), RuntimeError> {
+        match message {
+            Message::Deliver(Envelope { target, event }) => {
+                // Events for an instance that has terminated are dropped.
+                if let Some(actor) = self.actors.get_mut(&target.id) {
+                    actor.push(event);
+                    self.ready.push_back(target.id);
+                } else {
+                    self.shared.pending.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+            Message::Adopt(actor) => {
+                self.actors.insert(actor.id, *actor);
+                self.shared.pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lets an instance handle the next event in its inbox and passes on
+    /// whatever it sent. The event only stops counting as pending once
+    /// everything it caused is on its way, so the pool cannot look quiet
+    /// in between.
+    fn handle(&mut self, id: u32) -> Result<(), RuntimeError> {
+        let mut outbox = Outbox::default();
+        if let Some(actor) = self.actors.get_mut(&id) {
+            if let Some(event) = actor.poll() {
+                dispatch(actor, event, self.globals, &mut outbox)?;
+                self.shared.events.fetch_add(1, Ordering::SeqCst);
+
+                let unparked = actor.unpark();
+                self.shared.pending.fetch_add(unparked, Ordering::SeqCst);
+                self.ready.extend(std::iter::repeat_n(id, unparked));
+                if actor.terminated {
+                    self.actors.remove(&id);
+                }
+            }
+        }
+
+        self.route(outbox)?;
+        self.shared.pending.fetch_sub(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Starts spawned instances here and hands them to their owners, then
+    /// routes every send to the worker owning its target. Both go through
+    /// the same channels in that order, so an instance is always adopted
+    /// before any event for it arrives.
+    fn route(&mut self, mut outbox: Outbox) -> Result<(), RuntimeError> {
+        while !outbox.spawned.is_empty() {
+            for mut actor in std::mem::take(&mut outbox.spawned) {
+                start(&mut actor, self.globals, &mut outbox)?;
+                if !actor.terminated {
+                    self.post(actor.id, Message::Adopt(Box::new(actor)));
+                }
+            }
+        }
+
+        for envelope in outbox.sends {
+            self.post(envelope.target.id, Message::Deliver(envelope));
+        }
+
+        self.delayed.extend(outbox.delayed);
+        Ok(())
+    }
+
+    fn post(&self, id: u32, message: Message) {
+        self.shared.pending.fetch_add(1, Ordering::SeqCst);
+        // Every worker holds a sender to every other, so no channel closes
+        // while the pool is running.
+        let _ = self.routes[owner(id, self.routes.len())].send(message);
+    }
+
+    /// Takes in whatever is still waiting in the channel once every worker
+    /// has stopped, which only happens when one of them failed.
+    fn drain(&mut self) {
+        while let Ok(message) = self.inbox.try_recv() {
+            let _ = self.receive(message);
+        }
+    }
+}
+
+impl EvalEngine {
+    /// Like `until_quiescent`, but handles events on a pool of `workers`
+    /// threads. Each instance is owned by one worker, so it still handles
+    /// its own events one at a time and in the order they arrived, while
+    /// different instances run in parallel. Timers fire between rounds, one
+    /// at a time, as they do on a single thread. The scheduling policy is
+    /// not used: the order in which instances take turns is up to the
+    /// threads. Returns how many events were handled.
+    pub fn until_quiescent_threaded(&mut self, workers: usize) -> Result<usize, ProteusError> {
+        let workers = workers.max(1);
+        let mut events = self.run_round(workers)?;
+
+        while self.next_timer().is_some() {
+            self.fire_timer()?;
+            events += self.run_round(workers)?;
+        }
+
+        Ok(events)
+    }
+
+    /// Hands every instance to its worker, runs the pool until no event is
+    /// left, then takes the instances back.
+    fn run_round(&mut self, workers: usize) -> Result<usize, ProteusError> {
+        let waiting = self.scheduler.take_ready();
+        if waiting.is_empty() {
+            return Ok(0);
+        }
+
+        let shared = Shared { pending: AtomicUsize::new(waiting.len()), ..Shared::default() };
+        let (routes, inboxes): (Vec<_>, Vec<_>) = (0..workers).map(|_| mpsc::channel()).unzip();
+        let mut pool: Vec<Worker> = inboxes.into_iter()
+            .map(|inbox| Worker {
+                actors: BTreeMap::new(),
+                ready: VecDeque::new(),
+                delayed: vec![],
+                inbox,
+                routes: routes.clone(),
+                globals: &self.globals,
+                shared: &shared,
+            })
+            .collect();
+
+        for (id, actor) in std::mem::take(&mut self.instances) {
+            pool[owner(id, workers)].actors.insert(id, actor);
+        }
+        for id in waiting {
+            pool[owner(id, workers)].ready.push_back(id);
+        }
+        drop(routes);
+
+        let results: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = pool.iter_mut()
+                .map(|worker| thread::Builder::new()
+                    .stack_size(MAX_CALL_DEPTH * STACK_PER_CALL)
+                    .spawn_scoped(scope, move || {
+                        let result = worker.run();
+                        if result.is_err() {
+                            worker.shared.failed.store(true, Ordering::SeqCst);
+                        }
+                        result
+                    })
+                    .expect("failed to start a worker thread"))
+                .collect();
+
+            handles.into_iter().map(|handle| handle.join().expect("worker thread panicked")).collect()
+        });
+
+        let mut delayed = vec![];
+        for mut worker in pool {
+            worker.drain();
+            self.instances.append(&mut worker.actors);
+            for id in worker.ready {
+                self.scheduler.ready(id);
+            }
+            delayed.append(&mut worker.delayed);
+        }
+
+        self.arm(delayed);
+
+        match results.into_iter().find_map(Result::err) {
+            Some(err) => Err(self.fail(err)),
+            None => Ok(shared.events.into_inner()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ProteusError;
+    use crate::runtime::RuntimeError;
+    use crate::scheduler::Limit;
+    use crate::testing::{compile, var};
+
+    const PROGRAM: &str = "
+        event Ball(int);
+        event Score(int);
+
+        actor Tally {
+            int total = 0;
+            int hits = 0;
+            on Score(n) { total = total + n; hits = hits + 1; }
+        }
+
+        actor Player(int bonus) {
+            on Ball(n) {
+                Tally ! Score(n + bonus);
+                if n > 0 { self ! Ball(n - 1); }
+            }
+        }
+
+        actor Court {
+            statemachine {
+                initial Open;
+                state Open {
+                    on Score(n) goto Closed;
+                }
+                state Closed { }
+            }
+            entry {
+                actor left = spawn Player(1);
+                actor right = spawn Player(100);
+                left ! Ball(20);
+                right ! Ball(20);
+                Court ! Score(0);
+            }
+        }
+
+        func main() { }
+    ";
+
+    #[test]
+    fn threads_reach_the_same_state_as_one_thread() {
+        let mut sequential = compile(PROGRAM).unwrap();
+        let expected = sequential.run_main(Limit::Quiescent).unwrap();
+
+        for workers in [1, 2, 4] {
+            let mut threaded = compile(PROGRAM).unwrap();
+            let report = threaded.run_main_threaded(workers).unwrap();
+
+            assert_eq!(report.events_processed, expected.events_processed);
+            assert_eq!(report.final_states["Court"], expected.final_states["Court"]);
+            assert_eq!(var(&threaded, "Tally", "hits").to_string(), "42");
+            assert_eq!(var(&threaded, "Tally", "total"), var(&sequential, "Tally", "total"));
+        }
+    }
+
+    #[test]
+    fn deep_recursion_is_refused_on_worker_threads() {
+        let mut engine = compile("
+            event Go();
+            func f(int n) -> int {
+                if true {
+                    while true {
+                        match n { _ => { return 1 + (2 * (f(n + 1) + 3)); } }
+                    }
+                }
+                return 0;
+            }
+            actor A { on Go() { int x = f(0); } }
+            func main() { A ! Go(); }
+        ").unwrap();
+
+        let Err(ProteusError::Runtime { error, .. }) = engine.run_main_threaded(2) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(*error, RuntimeError::StackOverflow("f".to_string()));
+    }
+}
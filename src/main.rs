@@ -1,33 +1,132 @@
 mod ast;
+mod clock;
+mod error;
+mod executor;
 mod eval;
+mod module;
+mod resolve;
+mod runtime;
+mod scheduler;
+mod source;
+#[cfg(test)]
+mod testing;
 mod typecheck;
 
-use std::borrow::BorrowMut;
-use std::cell::RefCell;
-use egui::Rgba;
-use egui::{Area, Color32, emath, Order, Painter, Pos2, Rect, Sense, Stroke, Ui, Vec2};
-use egui::epaint::CubicBezierShape;
+use egui::{Color32, emath, Pos2, Rect, Sense, Stroke, Ui, Vec2};
 use crate::emath::Align2;
-use crate::eval::{eval_program, EvalEngine};
+use crate::clock::SystemClock;
+use crate::error::ProteusError;
+use crate::eval::EvalEngine;
+use crate::scheduler::{Limit, Policy};
 use std::default::Default;
-use std::rc::Rc;
-use egui::Event::PointerButton;
-use multimap::MultiMap;
+use std::io::Read;
 use std::collections::HashMap;
 
+const USAGE: &str = "usage: proteus-rs [FILE | -] [--path DIR]... [[--seed N] [--steps N | --until MS] | --workers N] [--realtime]";
+
+/// What to run and how, as given on the command line. A file named `-` is
+/// read from standard input.
+#[derive(Debug)]
+struct Options {
+    file: String,
+    paths: Vec<String>,
+    policy: Policy,
+    limit: Limit,
+    workers: Option<usize>,
+    realtime: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options { file: "tests/lights_toggle.pro".to_string(), paths: vec![], policy: Policy::Fifo, limit: Limit::Quiescent, workers: None, realtime: false };
+        // The flag that set the limit, if any.
+        let mut limited: Option<String> = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("`{}` needs a value", arg));
+            let mut number = || -> Result<u64, String> {
+                let value = value()?;
+                value.parse().map_err(|_| format!("`{}` needs a number, found `{}`", arg, value))
+            };
+
+            match arg.as_str() {
+                "--seed" => options.policy = Policy::Random { seed: number()? },
+                "--steps" | "--until" => {
+                    let number = number()?;
+                    if let Some(earlier) = limited.replace(arg.clone()) {
+                        return Err(format!("`{}` cannot be combined with `{}`", arg, earlier));
+                    }
+                    options.limit = if arg == "--steps" { Limit::Steps(number as usize) } else { Limit::Time(number) };
+                }
+                "--workers" => options.workers = Some(number()? as usize),
+                "--path" => options.paths.push(value()?),
+                "--realtime" => options.realtime = true,
+                flag if flag.starts_with("--") => return Err(format!("unknown option `{}`", flag)),
+                file => options.file = file.to_string(),
+            }
+        }
+
+        // The threaded executor always runs until quiescent and leaves the
+        // order to the threads.
+        let seeded = (options.policy != Policy::Fifo).then(|| "--seed".to_string());
+        if let (Some(_), Some(flag)) = (options.workers, limited.or(seeded)) {
+            return Err(format!("`--workers` cannot be combined with `{}`", flag));
+        }
+
+        Ok(options)
+    }
+}
+
+fn load(engine: &mut EvalEngine, file: &str) -> Result<(), ProteusError> {
+    if file != "-" {
+        return engine.load_from_file(file);
+    }
+
+    let mut text = String::new();
+    std::io::stdin().read_to_string(&mut text)
+        .map_err(|error| ProteusError::Io { path: "<stdin>".to_string(), error })?;
+    engine.load_from_string(&text)
+}
+
 fn main() {
+    let options = Options::parse(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("error: {}\n{}", err, USAGE);
+        std::process::exit(2);
+    });
+
     let mut engine = EvalEngine::default();
+    engine.set_policy(options.policy);
+    if options.realtime {
+        engine.clock = Box::new(SystemClock::new());
+    }
+    for path in &options.paths {
+        engine.add_search_path(path);
+    }
+
+    if let Err(err) = load(&mut engine, &options.file).and_then(|_| engine.compile()) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 
-    engine.load_from_file("tests/lights.pro").expect("Program loading failed");
-    assert!(engine.compile().is_ok());
-    println!("{:#?}", engine);
+    let run = match options.workers {
+        Some(workers) => engine.run_main_threaded(workers),
+        None => engine.run_main(options.limit),
+    };
+    match run {
+        Ok(report) => print!("{}", report),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
 
     let native_options = eframe::NativeOptions {
         initial_window_size: Some(eframe::egui::vec2(1600., 800.)),
         ..Default::default()
     };
 
-    eframe::run_native("Proteus", native_options, Box::new(|cc| Box::new(ProteusApp::new())));
+    eframe::run_native("Proteus", native_options, Box::new(|_cc| Box::new(ProteusApp::new())))
+        .expect("Editor failed to start");
 }
 
 #[derive(Clone)]
@@ -77,10 +176,8 @@ enum NodeSide {
 #[derive(Debug)]
 #[derive(Default)]
 struct NodePort {
-    id: usize,
     pos: Pos2,
     side: NodeSide,
-    delta: Pos2,
     node: usize,
 }
 
@@ -96,15 +193,13 @@ struct EditorState {
 
 impl ProteusApp {
     pub fn new() -> ProteusApp {
-        let mut app = ProteusApp {
+        ProteusApp {
             node_count: 0,
             state: EditorState::default(),
             timer: delta::Timer::new(),
             delta: 0.0f64,
             time: 0.0,
-        };
-
-        app
+        }
     }
 }
 
@@ -182,8 +277,7 @@ fn find_node_side(node_id: NodeId, mouse: &Pos2, editor_state: &EditorState) ->
 fn get_all_subs_of_node(node_id: NodeId, editor_state: &mut EditorState) -> Vec<NodeId> {
     let mut all_subs = vec![];
     let mut candidates = editor_state.nodes.get(&node_id).unwrap().sub_nodes.clone();
-    while !candidates.is_empty() {
-        let next = candidates.pop().unwrap();
+    while let Some(next) = candidates.pop() {
         if !all_subs.contains(&next) {
             all_subs.push(next);
             candidates.append(&mut editor_state.nodes.get(&next).unwrap().sub_nodes.clone());
@@ -195,14 +289,14 @@ fn get_all_subs_of_node(node_id: NodeId, editor_state: &mut EditorState) -> Vec<
 
 fn move_all_subnodes_by(node_id: NodeId, delta: &Vec2, editor_state: &mut EditorState) {
     let subs = get_all_subs_of_node(node_id, editor_state);
-    for (_id, port) in &mut editor_state.node_ports {
+    for port in editor_state.node_ports.values_mut() {
         if subs.contains(&port.node) {
             port.pos += *delta;
         }
     }
 }
 
-fn draw_node(ui: &mut Ui, node_id: NodeId, time: f64, parent_pos: Pos2, node_count: &mut usize, editor_state: &mut EditorState) {
+fn draw_node(ui: &mut Ui, node_id: NodeId, parent_pos: Pos2, node_count: &mut usize, editor_state: &mut EditorState) {
 
     let mut config = {
         let node = editor_state.nodes.get(&node_id).unwrap();
@@ -210,11 +304,11 @@ fn draw_node(ui: &mut Ui, node_id: NodeId, time: f64, parent_pos: Pos2, node_cou
     };
 
     let rect = {
-        let mut node = editor_state.nodes.get_mut(&node_id).unwrap();
+        let node = editor_state.nodes.get_mut(&node_id).unwrap();
         Rect::from_min_size(parent_pos + node.config.pos.to_vec2(), node.config.size)
     };
 
-    let mut response = ui.allocate_rect(rect,
+    let response = ui.allocate_rect(rect,
     egui::Sense::click_and_drag()
             .union(egui::Sense::hover())
             .union(egui::Sense::click()));
@@ -256,13 +350,13 @@ fn draw_node(ui: &mut Ui, node_id: NodeId, time: f64, parent_pos: Pos2, node_cou
                             let end_rect = editor_state.node_rects.get(node_id).unwrap();
 
                             let first_port_id = editor_state.node_ports.len();
-                            let (side1, min1, dist1) = find_node_side(start, &port_pos, editor_state);
-                            let start_port = NodePort { id: first_port_id, node: start, pos: port_pos - start_rect.min.to_vec2(), side: side1, delta: dist1 };
+                            let (side1, _, _) = find_node_side(start, &port_pos, editor_state);
+                            let start_port = NodePort { node: start, pos: port_pos - start_rect.min.to_vec2(), side: side1 };
                             editor_state.node_ports.insert(first_port_id, start_port);
 
                             let second_port_id = editor_state.node_ports.len();
-                            let (side2, min2, dist2) = find_node_side(*node_id, &pointer_pos, editor_state);
-                            let end_port = NodePort { id: second_port_id, node: *node_id, pos: pointer_pos - end_rect.min.to_vec2(), side: side2, delta: dist2 };
+                            let (side2, _, _) = find_node_side(*node_id, &pointer_pos, editor_state);
+                            let end_port = NodePort { node: *node_id, pos: pointer_pos - end_rect.min.to_vec2(), side: side2 };
                             editor_state.node_ports.insert(second_port_id, end_port);
 
                             editor_state.connections.push((first_port_id, second_port_id));
@@ -304,11 +398,9 @@ fn draw_node(ui: &mut Ui, node_id: NodeId, time: f64, parent_pos: Pos2, node_cou
                 let old_size = config.size;
                 config.size += delta;
 
-                for (_id, port) in &mut editor_state.node_ports {
-                    if port.node == node_id {
-                        if port.side == NodeSide::Right || port.side == NodeSide::Bottom {
-                            port.pos += delta;
-                        }
+                for port in editor_state.node_ports.values_mut() {
+                    if port.node == node_id && (port.side == NodeSide::Right || port.side == NodeSide::Bottom) {
+                        port.pos += delta;
                     }
                 }
 
@@ -350,7 +442,7 @@ fn draw_node(ui: &mut Ui, node_id: NodeId, time: f64, parent_pos: Pos2, node_cou
             config.dragged = false;
         } else if response.drag_started_by(egui::PointerButton::Primary) {
             let mouse_pos = response.hover_pos().unwrap();
-            let (side, min, dist) = find_node_side(node_id, &mouse_pos, editor_state);
+            let (_side, min, dist) = find_node_side(node_id, &mouse_pos, editor_state);
             editor_state.connecting = Option::Some((node_id, min + dist.to_vec2()));
         }
     }
@@ -406,30 +498,28 @@ fn draw_node(ui: &mut Ui, node_id: NodeId, time: f64, parent_pos: Pos2, node_cou
         if let Some(pointer_pos) = ui.ctx().pointer_hover_pos() {
             draw_port(ui, pointer_pos, true);
         }
-    } else if internal_hover {
-        if response.double_clicked_by(egui::PointerButton::Primary) {
-            *node_count += 1;
-            let next_pos = (response.hover_pos().unwrap() - parent_pos - config.pos.to_vec2()).to_pos2();
-            let next_size = Vec2::new(200.0, 150.0);
-            let id = *node_count;
-            let sub_node = Node {
-                id: *node_count,
-                config: NodeConfig {
-                    pos: next_pos,
-                    size: next_size,
-                    ..Default::default()
-                },
-                parent: Some(node_id),
-                sub_nodes: vec![] };
-
-            editor_state.nodes.insert(id, sub_node);
-            editor_state.nodes.get_mut(&node_id).unwrap().sub_nodes.push(id);
-            editor_state.node_rects.insert(id, Rect::from_min_size(next_pos, next_size));
-        }
+    } else if internal_hover && response.double_clicked_by(egui::PointerButton::Primary) {
+        *node_count += 1;
+        let next_pos = (response.hover_pos().unwrap() - parent_pos - config.pos.to_vec2()).to_pos2();
+        let next_size = Vec2::new(200.0, 150.0);
+        let id = *node_count;
+        let sub_node = Node {
+            id: *node_count,
+            config: NodeConfig {
+                pos: next_pos,
+                size: next_size,
+                ..Default::default()
+            },
+            parent: Some(node_id),
+            sub_nodes: vec![] };
+
+        editor_state.nodes.insert(id, sub_node);
+        editor_state.nodes.get_mut(&node_id).unwrap().sub_nodes.push(id);
+        editor_state.node_rects.insert(id, Rect::from_min_size(next_pos, next_size));
     }
 
     for sub in editor_state.nodes.get(&node_id).unwrap().sub_nodes.clone() {
-        draw_node(ui, sub, time, parent_pos + config.pos.to_vec2(), node_count, editor_state);
+        draw_node(ui, sub, parent_pos + config.pos.to_vec2(), node_count, editor_state);
     }
 
     if let Some((_, pos)) = editor_state.connecting {
@@ -476,7 +566,7 @@ impl eframe::App for ProteusApp {
             let nodes: Vec<_> = self.state.nodes.clone().into_keys().collect();
             for id in &nodes {
                 if self.state.nodes.get(id).unwrap().parent.is_none() {
-                    draw_node(ui, *id, self.time, Pos2::ZERO, &mut self.node_count, &mut self.state);
+                    draw_node(ui, *id, Pos2::ZERO, &mut self.node_count, &mut self.state);
                 }
             }
 
@@ -485,4 +575,21 @@ impl eframe::App for ProteusApp {
             }
         });
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Options;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        Options::parse(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn conflicting_run_options_are_usage_errors() {
+        assert_eq!(parse("--workers 4").unwrap().workers, Some(4));
+        assert_eq!(parse("--seed 1 --workers 4").unwrap_err(), "`--workers` cannot be combined with `--seed`");
+        assert_eq!(parse("--workers 4 --until 500").unwrap_err(), "`--workers` cannot be combined with `--until`");
+        assert_eq!(parse("--steps 5 --until 500").unwrap_err(), "`--until` cannot be combined with `--steps`");
+    }
+}
@@ -0,0 +1,175 @@
+use std::path::{Path, PathBuf};
+use crate::error::ProteusError;
+use crate::eval::*;
+use crate::source::Source;
+use crate::typecheck::{Diagnostic, DiagnosticKind};
+
+/* MODULES */
+
+/// The name a file is imported under: `lib/timers.pro` is module `timers`.
+/// Every loaded file must have a different one.
+fn module_name(path: &Path) -> String {
+    path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn read(path: &Path) -> Result<String, ProteusError> {
+    std::fs::read_to_string(path)
+        .map_err(|error| ProteusError::Io { path: path.display().to_string(), error })
+}
+
+impl EvalEngine {
+    /// Adds a directory to look in for imported files that are not found
+    /// next to the file importing them.
+    pub fn add_search_path(&mut self, path: impl Into<PathBuf>) {
+        self.search_paths.push(path.into());
+    }
+
+    /// Looks for `file` next to the importing file first, then in each
+    /// search path in the order they were added.
+    fn find_module(&self, dir: &Path, file: &str) -> Option<PathBuf> {
+        std::iter::once(dir)
+            .chain(self.search_paths.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(file))
+            .find(|path| path.is_file())
+    }
+
+    /// Parses a unit and loads everything it imports first. `chain` holds the
+    /// files currently being loaded, so an import leading back into one of
+    /// them is reported as a cycle instead of recursing forever.
+    pub(crate) fn load_unit(&mut self, key: &str, dir: &Path, source: Source, chain: &mut Vec<PathBuf>) -> Result<(), ProteusError> {
+        let mut unit = self.parse_unit(key, source)?;
+
+        for import in &mut unit.imports {
+            let fail = |kind| {
+                ProteusError::Semantic(vec![Diagnostic::new(&unit.source.name, kind, &unit.source, import.span)])
+            };
+
+            let Some(path) = self.find_module(dir, &import.file) else {
+                return Err(fail(DiagnosticKind::UnknownModule(import.file.clone())));
+            };
+            let path = path.canonicalize()
+                .map_err(|error| ProteusError::Io { path: path.display().to_string(), error })?;
+
+            if let Some(start) = chain.iter().position(|loading| *loading == path) {
+                let cycle: Vec<_> = chain[start..].iter().chain([&path])
+                    .map(|path| module_name(path))
+                    .collect();
+                return Err(fail(DiagnosticKind::ImportCycle(cycle.join(" -> "))));
+            }
+
+            // Units are keyed by module name, so two files with the same
+            // name in different directories cannot both be loaded.
+            let module = module_name(&path);
+            let loaded = self.modules.iter().chain(chain.iter())
+                .find(|loaded| **loaded != path && module_name(loaded) == module);
+            if let Some(loaded) = loaded {
+                return Err(fail(DiagnosticKind::ModuleClash { module, file: loaded.display().to_string() }));
+            }
+
+            import.module = module;
+            if self.modules.contains(&path) {
+                continue;
+            }
+
+            let text = read(&path)?;
+            let source = Source::new(self.next_file_id(), &path.display().to_string(), text);
+            let parent = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+            chain.push(path.clone());
+            self.load_unit(&import.module, &parent, source, chain)?;
+            chain.pop();
+            self.modules.insert(path);
+        }
+
+        self.units.insert(key.to_string(), unit);
+        Ok(())
+    }
+
+    pub fn load_from_file(&mut self, filepath: &str) -> Result<(), ProteusError> {
+        let path = Path::new(filepath);
+        let text = read(path)?;
+        let canonical = path.canonicalize()
+            .map_err(|error| ProteusError::Io { path: filepath.to_string(), error })?;
+        if self.modules.contains(&canonical) {
+            return Ok(());
+        }
+
+        let dir = canonical.parent().map(Path::to_path_buf).unwrap_or_default();
+
+        let source = Source::new(self.next_file_id(), filepath, text);
+        self.load_unit(&module_name(path), &dir, source, &mut vec![canonical.clone()])?;
+        self.modules.insert(canonical);
+        self.link();
+        Ok(())
+    }
+
+    /// Loads a program held in memory. Its imports are looked up relative to
+    /// the working directory.
+    pub fn load_from_string(&mut self, text: &str) -> Result<(), ProteusError> {
+        let source = Source::new(self.next_file_id(), "<string>", text.to_string());
+        self.load_unit("", Path::new("."), source, &mut vec![])?;
+        self.link();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ProteusError;
+    use crate::eval::EvalEngine;
+    use crate::scheduler::Limit;
+    use crate::testing::var;
+    use crate::typecheck::DiagnosticKind;
+
+    #[test]
+    fn imports_are_found_next_to_the_importing_file() {
+        let mut engine = EvalEngine::default();
+        engine.load_from_file("tests/modules/app.pro").unwrap();
+        engine.compile().unwrap();
+        engine.run_main(Limit::Quiescent).unwrap();
+
+        assert_eq!(var(&engine, "Counter", "total").to_string(), "6");
+    }
+
+    #[test]
+    fn missing_imports_are_reported() {
+        let mut engine = EvalEngine::default();
+        let Err(ProteusError::Io { path, .. }) = engine.load_from_file("tests/missing.pro") else {
+            panic!("expected an io error");
+        };
+        assert_eq!(path, "tests/missing.pro");
+
+        let Err(ProteusError::Semantic(diagnostics)) = engine.load_from_string(r#"import "missing.pro";"#) else {
+            panic!("expected an unknown module");
+        };
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::UnknownModule("missing.pro".to_string()));
+    }
+
+    #[test]
+    fn use_names_something_the_module_declares() {
+        let mut engine = EvalEngine::default();
+        engine.add_search_path("tests/modules/a");
+        engine.load_from_string(r#"
+            use util::Ping;
+            use util::Pong;
+        "#).unwrap();
+
+        let Err(ProteusError::Semantic(diagnostics)) = engine.compile() else {
+            panic!("expected an unknown import");
+        };
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::UnknownImport { module: "util".to_string(), name: "Pong".to_string() });
+    }
+
+    #[test]
+    fn files_with_the_same_name_cannot_both_be_imported() {
+        let mut engine = EvalEngine::default();
+        let Err(ProteusError::Semantic(diagnostics)) = engine.load_from_file("tests/modules/clash.pro") else {
+            panic!("expected the second import to be rejected");
+        };
+
+        assert_eq!(diagnostics.len(), 1);
+        assert!(matches!(&diagnostics[0].kind, DiagnosticKind::ModuleClash { module, file }
+            if module == "util" && file.ends_with("util.pro")));
+    }
+}
@@ -0,0 +1,600 @@
+use std::collections::{HashMap, HashSet};
+use multimap::MultiMap;
+use crate::ast::{Span, StateKind};
+use crate::eval::*;
+use crate::runtime::BUILTINS;
+use crate::source::Source;
+use crate::typecheck::{Diagnostic, DiagnosticKind};
+
+/// Every state declared in one actor's state machine, keyed by name. State
+/// names are unique per actor, which is what lets `goto` and `initial` refer
+/// to them with a bare identifier.
+struct StateIndex<'a> {
+    states: HashMap<&'a str, (String, &'a State)>,
+    order: Vec<&'a str>,
+}
+
+impl<'a> StateIndex<'a> {
+    fn build(root: &'a State, source: &Source, diagnostics: &mut Vec<Diagnostic>) -> Self {
+        let mut index = StateIndex { states: HashMap::new(), order: vec![] };
+        index.collect(&root.name, root, source, diagnostics);
+        index
+    }
+
+    fn collect(&mut self, path: &str, state: &'a State, source: &Source, diagnostics: &mut Vec<Diagnostic>) {
+        for name in &state.order {
+            let sub_path = format!("{}.{}", path, name);
+            if self.states.contains_key(name.as_str()) {
+                let kind = DiagnosticKind::DuplicateState(name.clone());
+                diagnostics.push(Diagnostic::new(&sub_path, kind, source, state.subs[name].span));
+                continue;
+            }
+
+            let sub = &state.subs[name];
+            self.states.insert(name, (sub_path.clone(), sub));
+            self.order.push(name);
+            self.collect(&sub_path, sub, source, diagnostics);
+        }
+
+        for (name, span) in &state.duplicates {
+            let kind = DiagnosticKind::DuplicateState(name.clone());
+            diagnostics.push(Diagnostic::new(&format!("{}.{}", path, name), kind, source, *span));
+        }
+    }
+}
+
+struct Resolver<'a> {
+    root: &'a State,
+    index: StateIndex<'a>,
+    reachable: HashSet<&'a str>,
+    pending: Vec<&'a State>,
+}
+
+impl<'a> Resolver<'a> {
+    /// Marks a state as active, along with its ancestors, the other regions
+    /// of any parallel ancestor, and the sub-states entered below it.
+    fn enter(&mut self, name: &str) {
+        let Some(path) = self.root.path_to(name) else { return; };
+
+        let mut state = self.root;
+        for step in &path {
+            if state.kind == StateKind::Parallel {
+                for region in state.order.iter().filter(|region| *region != step) {
+                    self.enter_default(&state.subs[region]);
+                }
+            }
+
+            state = &state.subs[step];
+            self.mark(state);
+        }
+
+        self.enter_default(state);
+    }
+
+    /// Marks what entering `state` without a target below it activates:
+    /// every region of a parallel state, or the chain of `initial` states.
+    fn enter_default(&mut self, state: &'a State) {
+        self.mark(state);
+        match state.kind {
+            StateKind::Parallel => {
+                for region in &state.order {
+                    self.enter_default(&state.subs[region]);
+                }
+            }
+            _ => {
+                if let Some(initial) = state.subs.get(&state.initial) {
+                    self.enter_default(initial);
+                }
+            }
+        }
+    }
+
+    fn mark(&mut self, state: &'a State) {
+        if self.reachable.insert(state.name.as_str()) {
+            self.pending.push(state);
+        }
+    }
+}
+
+fn check_initial(path: &str, state: &State, source: &Source, diagnostics: &mut Vec<Diagnostic>) {
+    if state.kind == StateKind::Parallel {
+        if !state.initial.is_empty() {
+            diagnostics.push(Diagnostic::new(path, DiagnosticKind::ParallelInitial, source, state.span));
+        }
+    } else if state.initial.is_empty() {
+        if !state.subs.is_empty() {
+            diagnostics.push(Diagnostic::new(path, DiagnosticKind::MissingInitial, source, state.span));
+        }
+    } else if !state.subs.contains_key(&state.initial) {
+        let kind = DiagnosticKind::UnknownInitial(state.initial.clone());
+        diagnostics.push(Diagnostic::new(path, kind, source, state.span));
+    } else if state.subs[&state.initial].kind == StateKind::Choice {
+        let kind = DiagnosticKind::ChoiceInitial(state.initial.clone());
+        diagnostics.push(Diagnostic::new(path, kind, source, state.span));
+    }
+}
+
+/// Whether a state can reach a final sub-state, or for a parallel state,
+/// whether every one of its regions can.
+fn can_finish(state: &State) -> bool {
+    match state.kind {
+        StateKind::Parallel => !state.subs.is_empty() && state.subs.values().all(can_finish),
+        _ => state.subs.values().any(|sub| sub.kind == StateKind::Final),
+    }
+}
+
+/// Every handler of `transitions` in the order they were written, so
+/// what is reported about them comes out in the same order on every run.
+pub fn in_order(transitions: &MultiMap<String, Transition>) -> Vec<&Transition> {
+    let mut handlers: Vec<_> = transitions.iter_all().flat_map(|(_, handlers)| handlers).collect();
+    handlers.sort_by_key(|trans| trans.span.start);
+    handlers
+}
+
+/// Reports handlers declared in one place that can never be taken, because
+/// another handler for the same event without a guard is tried before
+/// them. Handlers are tried in declaration order with `else` last, and the
+/// branches of a choice the same way.
+fn check_overlaps(path: &str, transitions: &MultiMap<String, Transition>, source: &Source, diagnostics: &mut Vec<Diagnostic>) {
+    let mut shadowed_handlers = vec![];
+    for (event, handlers) in transitions.iter_all() {
+        if matches!(event.as_str(), "_ENTRY" | "_EXIT" | "_AFTER") {
+            continue;
+        }
+
+        let (otherwise, guarded): (Vec<_>, Vec<_>) = handlers.iter().partition(|trans| trans.otherwise);
+        let mut shadowed = false;
+        for trans in guarded.into_iter().chain(otherwise) {
+            if shadowed {
+                shadowed_handlers.push((event, trans));
+            }
+            shadowed |= trans.conditions.is_empty();
+        }
+    }
+
+    shadowed_handlers.sort_by_key(|(_, trans)| trans.span.start);
+    for (event, trans) in shadowed_handlers {
+        let kind = match event.as_str() {
+            "_CHOICE" => DiagnosticKind::ShadowedBranch,
+            "_DONE" => DiagnosticKind::ShadowedHandler("done".to_string()),
+            _ => DiagnosticKind::ShadowedHandler(event.clone()),
+        };
+        diagnostics.push(Diagnostic::new(path, kind, source, trans.span));
+    }
+}
+
+/// Reports `entry` and `exit` blocks declared more than once in one place,
+/// since only the first of each would ever run.
+fn check_blocks(path: &str, transitions: &MultiMap<String, Transition>, source: &Source, diagnostics: &mut Vec<Diagnostic>) {
+    for (event, block) in [("_ENTRY", "entry"), ("_EXIT", "exit")] {
+        for trans in transitions.get_vec(event).into_iter().flatten().skip(1) {
+            let kind = DiagnosticKind::DuplicateBlock(block.to_string());
+            diagnostics.push(Diagnostic::new(path, kind, source, trans.span));
+        }
+    }
+}
+
+fn resolve_statemachine(root: &State, source: &Source) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let index = StateIndex::build(root, source, &mut diagnostics);
+
+    let states = std::iter::once((root.name.clone(), root))
+        .chain(index.order.iter().map(|name| index.states[name].clone()));
+
+    for (path, state) in states {
+        check_initial(&path, state, source, &mut diagnostics);
+        check_blocks(&path, &state.transitions, source, &mut diagnostics);
+        check_overlaps(&path, &state.transitions, source, &mut diagnostics);
+        if state.history.is_some() && state.subs.is_empty() {
+            diagnostics.push(Diagnostic::new(&path, DiagnosticKind::UselessHistory, source, state.span));
+        }
+        if let (Some(done), false) = (state.transitions.get("_DONE"), can_finish(state)) {
+            diagnostics.push(Diagnostic::new(&path, DiagnosticKind::NeverDone, source, done.span));
+        }
+
+        for name in &state.order {
+            let sub = &state.subs[name];
+            if (state.kind == StateKind::Parallel) != (sub.kind == StateKind::Region) {
+                let kind = DiagnosticKind::MisplacedRegion { parallel: state.kind == StateKind::Parallel };
+                diagnostics.push(Diagnostic::new(&format!("{}.{}", path, name), kind, source, sub.span));
+            }
+        }
+
+        for trans in in_order(&state.transitions) {
+            match trans.target.state() {
+                Some(target) if !index.states.contains_key(target) => {
+                    let kind = DiagnosticKind::UnknownTarget(target.to_string());
+                    diagnostics.push(Diagnostic::new(&path, kind, source, trans.span));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut resolver = Resolver { root, index, reachable: HashSet::new(), pending: vec![root] };
+    resolver.enter(&root.initial);
+    while let Some(state) = resolver.pending.pop() {
+        for (_, handlers) in state.transitions.iter_all() {
+            for trans in handlers.iter().filter_map(|trans| trans.target.state()) {
+                resolver.enter(trans);
+            }
+        }
+    }
+
+    for name in &resolver.index.order {
+        if !resolver.reachable.contains(name) {
+            let (path, state) = &resolver.index.states[name];
+            diagnostics.push(Diagnostic::new(path, DiagnosticKind::UnreachableState, source, state.span));
+        }
+    }
+
+    diagnostics
+}
+
+/// Resolves `goto` targets and `initial` declarations of every actor's state
+/// machine, and reports states and handlers that can never be reached.
+pub fn resolve_unit(unit: &InterpretationUnit) -> Vec<Diagnostic> {
+    let mut actors: Vec<_> = unit.actors.values().collect();
+    actors.sort_by_key(|actor| actor.id);
+
+    let mut diagnostics = vec![];
+    for actor in actors {
+        for span in &actor.malformed {
+            diagnostics.push(Diagnostic::new(&actor.name, DiagnosticKind::EventParameter, &unit.source, *span));
+        }
+        check_blocks(&actor.name, &actor.transitions, &unit.source, &mut diagnostics);
+        check_overlaps(&actor.name, &actor.transitions, &unit.source, &mut diagnostics);
+        if let Some(root) = &actor.statemachine {
+            diagnostics.extend(resolve_statemachine(root, &unit.source));
+        }
+    }
+
+    diagnostics
+}
+
+/* MODULES */
+
+/// The actors, events, records, enums and funcs a unit can refer to: its
+/// own declarations and whatever its imports bring in.
+#[derive(Default)]
+pub struct Namespace<'a> {
+    pub actors: HashMap<&'a str, &'a Actor>,
+    pub events: HashMap<&'a str, &'a EventSignature>,
+    pub records: HashMap<&'a str, &'a RecordSignature>,
+    pub enums: HashMap<&'a str, &'a EnumSignature>,
+    pub funcs: HashMap<&'a str, &'a FuncSignature>,
+}
+
+impl<'a> Namespace<'a> {
+    /// Adds the declarations of `unit`, or only the one called `only`.
+    /// Returns whether anything was added.
+    fn add(&mut self, unit: &'a InterpretationUnit, only: Option<&str>) -> bool {
+        let wanted = |name: &str| only.is_none_or(|only| only == name);
+        let before = self.len();
+
+        self.actors.extend(unit.actors.iter().filter(|(name, _)| wanted(name)).map(|(name, actor)| (name.as_str(), actor.as_ref())));
+        self.events.extend(unit.events.iter().filter(|(name, _)| wanted(name)).map(|(name, event)| (name.as_str(), event)));
+        self.records.extend(unit.records.iter().filter(|(name, _)| wanted(name)).map(|(name, record)| (name.as_str(), record)));
+        self.enums.extend(unit.enums.iter().filter(|(name, _)| wanted(name)).map(|(name, decl)| (name.as_str(), decl)));
+        self.funcs.extend(unit.funcs.iter().filter(|(name, _)| wanted(name)).map(|(name, func)| (name.as_str(), func.as_ref())));
+
+        self.len() > before
+    }
+
+    fn len(&self) -> usize {
+        self.actors.len() + self.events.len() + self.records.len() + self.enums.len() + self.funcs.len()
+    }
+}
+
+/// Builds the namespace of a unit from the loaded `units`, reporting `use`
+/// declarations that name something their module does not declare.
+pub fn namespace<'a>(unit: &'a InterpretationUnit, units: &'a MultiMap<String, InterpretationUnit>) -> (Namespace<'a>, Vec<Diagnostic>) {
+    let mut names = Namespace::default();
+    let mut diagnostics = vec![];
+
+    for import in &unit.imports {
+        let mut found = false;
+        for module in units.get_vec(&import.module).into_iter().flatten() {
+            found |= names.add(module, import.name.as_deref());
+        }
+
+        if let (false, Some(name)) = (found, &import.name) {
+            let kind = DiagnosticKind::UnknownImport { module: import.module.clone(), name: name.clone() };
+            diagnostics.push(Diagnostic::new(&unit.source.name, kind, &unit.source, import.span));
+        }
+    }
+
+    // A unit's own declarations take precedence over anything imported.
+    names.add(unit, None);
+    (names, diagnostics)
+}
+
+/// Reports actors, events, records, enums and funcs declared more than once,
+/// in the same unit or in different ones, or named after a built-in
+/// operation. Names are shared by the whole program once it runs, since
+/// actors, events and funcs are looked up by name alone, so they must be
+/// unique across every loaded file: two modules cannot both declare `Ping`
+/// even if neither imports the other. A clash names the file and line of
+/// the first declaration as well as pointing at the second.
+pub fn check_duplicates(units: &[&InterpretationUnit]) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut declared: HashMap<&str, (&InterpretationUnit, Span)> = HashMap::new();
+
+    for unit in units {
+        for (name, span) in &unit.redeclared {
+            let kind = DiagnosticKind::Redeclared(name.clone());
+            diagnostics.push(Diagnostic::new(&unit.source.name, kind, &unit.source, *span));
+        }
+
+        let mut decls: Vec<(&str, Span)> = unit.actors.values().map(|actor| (actor.name.as_str(), actor.span))
+            .chain(unit.events.values().map(|event| (event.name.as_str(), event.span)))
+            .chain(unit.records.values().map(|record| (record.name.as_str(), record.span)))
+            .chain(unit.enums.values().map(|decl| (decl.name.as_str(), decl.span)))
+            .chain(unit.funcs.values().map(|func| (func.func_name.as_str(), func.span)))
+            .collect();
+        decls.sort_by_key(|(_, span)| span.start);
+
+        for (name, span) in decls {
+            if BUILTINS.contains(&name) {
+                let kind = DiagnosticKind::BuiltinName(name.to_string());
+                diagnostics.push(Diagnostic::new(&unit.source.name, kind, &unit.source, span));
+                continue;
+            }
+
+            match declared.get(name) {
+                Some((first, first_span)) if first.source.id != unit.source.id => {
+                    let location = first.source.locate(*first_span);
+                    let kind = DiagnosticKind::DuplicateDeclaration {
+                        name: name.to_string(),
+                        module: first.name.clone(),
+                        first: format!("{}:{}", location.file, location.line),
+                    };
+                    diagnostics.push(Diagnostic::new(&unit.source.name, kind, &unit.source, span));
+                }
+                _ => {
+                    declared.insert(name, (unit, span));
+                }
+            }
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ProteusError;
+    use crate::eval::EvalEngine;
+    use crate::testing::diagnostics;
+    use crate::typecheck::DiagnosticKind;
+
+    #[test]
+    fn the_lights_example_goes_to_states_that_do_not_exist() {
+        let mut engine = EvalEngine::default();
+        engine.load_from_file("tests/lights.pro").unwrap();
+        let Err(ProteusError::Semantic(found)) = engine.compile() else {
+            panic!("expected unknown goto targets");
+        };
+        let found: Vec<_> = found.into_iter()
+            .map(|diagnostic| (diagnostic.context, diagnostic.kind))
+            .collect();
+
+        assert_eq!(found, vec![
+            ("Lights.LightsOff".to_string(), DiagnosticKind::UnknownTarget("B".to_string())),
+            ("Lights.LightsOn".to_string(), DiagnosticKind::UnknownTarget("A".to_string())),
+            ("Lights.LightsOn".to_string(), DiagnosticKind::UnreachableState),
+        ]);
+    }
+
+    #[test]
+    fn the_corrected_lights_example_resolves() {
+        let mut engine = EvalEngine::default();
+        engine.load_from_file("tests/lights_toggle.pro").unwrap();
+        engine.compile().unwrap();
+    }
+
+    #[test]
+    fn state_machine_problems() {
+        let found = diagnostics("
+            event Go();
+            actor A {
+                statemachine {
+                    initial Missing;
+                    state Idle {
+                        state Inner { }
+                        on Go() goto Nowhere;
+                    }
+                    state Lonely { }
+                    state Idle { }
+                }
+            }
+        ");
+
+        assert_eq!(found, vec![
+            DiagnosticKind::DuplicateState("Idle".to_string()),
+            DiagnosticKind::UnknownInitial("Missing".to_string()),
+            DiagnosticKind::MissingInitial,
+            DiagnosticKind::UnknownTarget("Nowhere".to_string()),
+            DiagnosticKind::UnreachableState,
+            DiagnosticKind::UnreachableState,
+            DiagnosticKind::UnreachableState,
+        ]);
+    }
+
+    #[test]
+    fn states_and_blocks_declared_twice() {
+        let mut engine = EvalEngine::default();
+        engine.load_from_string("
+            actor A {
+                entry { }
+                entry { }
+                statemachine {
+                    initial Idle;
+                    state Idle {
+                        exit { }
+                        exit { }
+                    }
+                    state Idle { }
+                }
+            }
+        ").unwrap();
+        let Err(ProteusError::Semantic(found)) = engine.compile() else {
+            panic!("expected the repeated declarations to be reported");
+        };
+        let found: Vec<_> = found.into_iter()
+            .map(|diagnostic| (diagnostic.kind, diagnostic.location.unwrap().line))
+            .collect();
+
+        assert_eq!(found, vec![
+            (DiagnosticKind::DuplicateBlock("entry".to_string()), 4),
+            (DiagnosticKind::DuplicateState("Idle".to_string()), 11),
+            (DiagnosticKind::DuplicateBlock("exit".to_string()), 9),
+        ]);
+    }
+
+    #[test]
+    fn regions_belong_to_parallel_states() {
+        let found = diagnostics("
+            actor A {
+                statemachine {
+                    initial Both;
+                    parallel Both {
+                        initial Left;
+                        region Left { }
+                        state Right { }
+                    }
+                    region Stray { }
+                }
+            }
+        ");
+
+        assert_eq!(found, vec![
+            DiagnosticKind::MisplacedRegion { parallel: false },
+            DiagnosticKind::ParallelInitial,
+            DiagnosticKind::MisplacedRegion { parallel: true },
+            DiagnosticKind::UnreachableState,
+        ]);
+    }
+
+    #[test]
+    fn history_needs_sub_states() {
+        let found = diagnostics("
+            actor A {
+                statemachine {
+                    initial Idle;
+                    state Idle { history; }
+                }
+            }
+        ");
+
+        assert_eq!(found, vec![DiagnosticKind::UselessHistory]);
+    }
+
+    #[test]
+    fn shadowed_handlers_and_branches() {
+        let found = diagnostics("
+            event Go();
+            actor A {
+                int n = 0;
+                statemachine {
+                    initial S;
+                    state S {
+                        on Go() goto C;
+                        on Go() goto T if n > 1 { }
+                    }
+                    choice C {
+                        else goto T;
+                        else goto S;
+                    }
+                    state T { }
+                }
+            }
+        ");
+
+        assert_eq!(found, vec![
+            DiagnosticKind::ShadowedHandler("Go".to_string()),
+            DiagnosticKind::ShadowedBranch,
+        ]);
+    }
+
+    #[test]
+    fn done_needs_a_final_state() {
+        let found = diagnostics("
+            actor A {
+                statemachine {
+                    initial S;
+                    state S {
+                        initial Inner;
+                        on done goto T;
+                        state Inner { }
+                    }
+                    state T { }
+                }
+            }
+        ");
+
+        assert_eq!(found, vec![DiagnosticKind::NeverDone]);
+    }
+
+    #[test]
+    fn names_declared_twice_in_one_file() {
+        let found = diagnostics("
+            func f() { }
+            func f() { }
+            record Mode { int speed; }
+            enum Mode { Low, High }
+        ");
+
+        assert_eq!(found, vec![
+            DiagnosticKind::Redeclared("f".to_string()),
+            DiagnosticKind::Redeclared("Mode".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn names_declared_in_two_programs() {
+        let mut engine = EvalEngine::default();
+        engine.load_from_string("event Ping();").unwrap();
+        engine.load_from_string("func Ping() { }").unwrap();
+
+        let Err(ProteusError::Semantic(found)) = engine.compile() else {
+            panic!("expected a duplicate declaration");
+        };
+        assert!(matches!(&found[0].kind, DiagnosticKind::DuplicateDeclaration { name, .. } if name == "Ping"));
+    }
+
+    #[test]
+    fn names_declared_in_two_files_point_at_both() {
+        let mut engine = EvalEngine::default();
+        engine.load_from_file("tests/modules/app.pro").unwrap();
+        engine.load_from_string("\nfunc twice(int n) -> int { return n + n; }").unwrap();
+
+        let Err(ProteusError::Semantic(found)) = engine.compile() else {
+            panic!("expected a duplicate declaration");
+        };
+        assert_eq!(found.len(), 1);
+        assert!(matches!(&found[0].kind, DiagnosticKind::DuplicateDeclaration { name, module, first }
+            if name == "twice" && module == "util" && first.ends_with("tests/modules/a/util.pro:3")));
+
+        let location = found[0].location.as_ref().unwrap();
+        assert_eq!((location.file.as_str(), location.line), ("<string>", 2));
+    }
+
+    #[test]
+    fn event_parameters_that_are_not_identifiers() {
+        let found = diagnostics(r#"
+            event Ev(int);
+            actor A {
+                on Ev(1) { }
+                statemachine {
+                    initial S;
+                    state S {
+                        on Ev("x") goto S;
+                    }
+                }
+            }
+        "#);
+
+        assert_eq!(found, vec![DiagnosticKind::EventParameter, DiagnosticKind::EventParameter]);
+    }
+}
@@ -0,0 +1,2290 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use crate::ast::*;
+use crate::error::ProteusError;
+use crate::eval::*;
+use crate::scheduler::Limit;
+use crate::source::Location;
+
+/* ERRORS */
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum RuntimeError {
+    UnknownActor(String),
+    UnknownEvent(String),
+    UnknownVariable(String),
+    UnknownFunction(String),
+    InvalidOperands { op: &'static str, left: VarType, right: VarType },
+    InvalidOperand { op: &'static str, operand: VarType },
+    ArgumentCount { func: String, expected: usize, found: usize },
+    VoidValue(String),
+    MissingReturn(String),
+    StackOverflow(String),
+    NonBoolCondition(VarType),
+    NoSelf,
+    NoSender,
+    UnknownState(String),
+    NoBranch(String),
+    IndexOutOfBounds { index: i64, len: usize },
+    MissingKey(String),
+    UnknownField(String),
+    LoopLimit,
+    DivisionByZero,
+    Overflow(&'static str),
+    Unsupported(String),
+    /// Another error, raised while running the code at `Span`.
+    At(Span, Box<RuntimeError>),
+}
+
+impl RuntimeError {
+    /// Records where an error was raised, unless a more precise location
+    /// was already recorded further in.
+    pub fn at(self, span: Span) -> Self {
+        match self {
+            RuntimeError::At(..) => self,
+            error => RuntimeError::At(span, Box::new(error)),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::UnknownActor(name) => write!(f, "no actor named `{}`", name),
+            RuntimeError::UnknownEvent(name) => write!(f, "no event named `{}`", name),
+            RuntimeError::UnknownVariable(name) => write!(f, "no variable named `{}` in scope", name),
+            RuntimeError::UnknownFunction(name) => write!(f, "no func named `{}`", name),
+            RuntimeError::InvalidOperands { op, left, right } =>
+                write!(f, "operator `{}` cannot be applied to `{}` and `{}`", op, left, right),
+            RuntimeError::InvalidOperand { op, operand } =>
+                write!(f, "operator `{}` cannot be applied to `{}`", op, operand),
+            RuntimeError::ArgumentCount { func, expected, found } =>
+                write!(f, "`{}` takes {} argument(s), {} given", func, expected, found),
+            RuntimeError::VoidValue(name) => write!(f, "`{}` returned no value", name),
+            RuntimeError::MissingReturn(name) => write!(f, "`{}` finished without returning a value", name),
+            RuntimeError::StackOverflow(name) =>
+                write!(f, "call to `{}` exceeds the maximum call depth of {}", name, MAX_CALL_DEPTH),
+            RuntimeError::NonBoolCondition(found) => write!(f, "condition must be `bool`, found `{}`", found),
+            RuntimeError::NoSelf => write!(f, "`self` used outside of an actor"),
+            RuntimeError::NoSender => write!(f, "the event being handled was not sent by an actor"),
+            RuntimeError::UnknownState(name) => write!(f, "no state named `{}`", name),
+            RuntimeError::NoBranch(name) => write!(f, "no branch of choice `{}` applies", name),
+            RuntimeError::IndexOutOfBounds { index, len } =>
+                write!(f, "index {} is out of bounds for a list of {} item(s)", index, len),
+            RuntimeError::MissingKey(key) => write!(f, "map has no entry for key {}", key),
+            RuntimeError::UnknownField(name) => write!(f, "no field named `{}`", name),
+            RuntimeError::LoopLimit =>
+                write!(f, "`while` loop exceeds the maximum of {} iterations", MAX_LOOP_ITERATIONS),
+            RuntimeError::DivisionByZero => write!(f, "division by zero"),
+            RuntimeError::Overflow(op) => write!(f, "integer overflow in `{}`", op),
+            RuntimeError::Unsupported(what) => write!(f, "{} is not supported by the interpreter yet", what),
+            RuntimeError::At(_, error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// How deeply funcs may call each other before the call is refused.
+pub const MAX_CALL_DEPTH: usize = 100;
+
+/// Stack a thread running handlers needs for each nested call, so that
+/// `MAX_CALL_DEPTH` calls fit before the depth check refuses the next one.
+pub const STACK_PER_CALL: usize = 256 * 1024;
+
+/// How many times a single `while` loop may run its body before it is
+/// assumed never to finish.
+pub const MAX_LOOP_ITERATIONS: usize = 10_000;
+
+/* OPERATORS */
+
+fn promote(l: Value, r: Value) -> (Value, Value) {
+    match (l, r) {
+        (Value::Int(a), Value::Float(b)) => (Value::Float(a as f64), Value::Float(b)),
+        (Value::Float(a), Value::Int(b)) => (Value::Float(a), Value::Float(b as f64)),
+        pair => pair,
+    }
+}
+
+fn arith(op: &'static str, l: Value, r: Value, int_op: fn(i64, i64) -> Option<i64>, float_op: fn(f64, f64) -> f64) -> Result<Value, RuntimeError> {
+    match promote(l, r) {
+        (Value::Int(a), Value::Int(b)) => int_op(a, b).map(Value::Int).ok_or(RuntimeError::Overflow(op)),
+        (Value::Float(a), Value::Float(b)) => Ok(Value::Float(float_op(a, b))),
+        (l, r) => Err(RuntimeError::InvalidOperands { op, left: l.var_type(), right: r.var_type() }),
+    }
+}
+
+fn add(l: Value, r: Value) -> Result<Value, RuntimeError> {
+    match (l, r) {
+        (Value::Str(a), Value::Str(b)) => Ok(Value::Str(a + &b)),
+        (l, r) => arith("+", l, r, i64::checked_add, |a, b| a + b),
+    }
+}
+
+fn divide(l: Value, r: Value) -> Result<Value, RuntimeError> {
+    match (&l, &r) {
+        (Value::Int(_), Value::Int(0)) => Err(RuntimeError::DivisionByZero),
+        _ => arith("/", l, r, i64::checked_div, |a, b| a / b),
+    }
+}
+
+fn equals(op: &'static str, l: Value, r: Value) -> Result<bool, RuntimeError> {
+    match promote(l, r) {
+        (l, r) if l.var_type() == r.var_type() => Ok(l == r),
+        (l, r) => Err(RuntimeError::InvalidOperands { op, left: l.var_type(), right: r.var_type() }),
+    }
+}
+
+fn compare(op: &'static str, l: Value, r: Value, test: fn(std::cmp::Ordering) -> bool) -> Result<Value, RuntimeError> {
+    let ordering = match promote(l, r) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(&b)),
+        (Value::Float(a), Value::Float(b)) => a.partial_cmp(&b),
+        (l, r) => return Err(RuntimeError::InvalidOperands { op, left: l.var_type(), right: r.var_type() }),
+    };
+
+    Ok(Value::Bool(ordering.map(test).unwrap_or(false)))
+}
+
+/* COLLECTIONS */
+
+/// Operations on lists, maps and strings that are called like funcs.
+/// `insert` and `remove` change a list or map in place, so their first
+/// argument must be something that can be assigned to.
+pub const BUILTINS: [&str; 3] = ["len", "insert", "remove"];
+
+/// One step from a value into a value it holds: an element of a list or
+/// map, or a field of a record.
+enum Key {
+    Index(Value),
+    Field(String),
+}
+
+/// The order map entries are kept in. Keys are ints, bools, strings, actor
+/// references or enum variants, and are never compared across those kinds.
+fn key_order(a: &Value, b: &Value) -> std::cmp::Ordering {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => a.cmp(b),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Str(a), Value::Str(b)) => a.cmp(b),
+        (Value::ActorRef(a), Value::ActorRef(b)) => a.id.cmp(&b.id),
+        (Value::Enum { variant: a, .. }, Value::Enum { variant: b, .. }) => a.cmp(b),
+        _ => std::cmp::Ordering::Equal,
+    }
+}
+
+/// Sets the value for `key`, adding an entry where it belongs if there
+/// is none yet.
+fn map_insert(entries: &mut Vec<(Value, Value)>, key: Value, value: Value) {
+    match entries.binary_search_by(|(k, _)| key_order(k, &key)) {
+        Ok(at) => entries[at].1 = value,
+        Err(at) => entries.insert(at, (key, value)),
+    }
+}
+
+/// Turns `index` into a position in a list of `len` items. `end` allows
+/// the position just past the last item, where an item can be inserted.
+fn position(index: &Value, len: usize, end: bool) -> Result<usize, RuntimeError> {
+    let limit = if end { len + 1 } else { len };
+    match index {
+        Value::Int(i) if *i >= 0 && (*i as usize) < limit => Ok(*i as usize),
+        Value::Int(i) => Err(RuntimeError::IndexOutOfBounds { index: *i, len }),
+        other => Err(RuntimeError::InvalidOperand { op: "[]", operand: other.var_type() }),
+    }
+}
+
+/// Finds what `key` refers to inside `val`.
+fn element<'v>(val: &'v mut Value, key: &Key) -> Result<&'v mut Value, RuntimeError> {
+    match (val, key) {
+        (Value::List { items, .. }, Key::Index(index)) => {
+            let at = position(index, items.len(), false)?;
+            Ok(&mut items[at])
+        }
+        (Value::Map { entries, .. }, Key::Index(key)) => {
+            match entries.binary_search_by(|(k, _)| key_order(k, key)) {
+                Ok(at) => Ok(&mut entries[at].1),
+                Err(_) => Err(RuntimeError::MissingKey(key.to_string())),
+            }
+        }
+        (Value::Record { fields, .. }, Key::Field(name)) => fields.iter_mut()
+            .find(|(field, _)| field == name)
+            .map(|(_, val)| val)
+            .ok_or_else(|| RuntimeError::UnknownField(name.clone())),
+        (other, Key::Index(_)) => Err(RuntimeError::InvalidOperand { op: "[]", operand: other.var_type() }),
+        (other, Key::Field(_)) => Err(RuntimeError::InvalidOperand { op: ".", operand: other.var_type() }),
+    }
+}
+
+/// Stores `val` at `key` inside `container`. Assigning to a key a map does
+/// not have yet adds an entry for it.
+fn store(container: &mut Value, key: Key, val: Value) -> Result<(), RuntimeError> {
+    match (container, key) {
+        (Value::Map { key: key_type, value, entries }, Key::Index(key)) => {
+            map_insert(entries, key.coerce(key_type), val.coerce(value));
+            Ok(())
+        }
+        (container, key) => {
+            let slot = element(container, &key)?;
+            *slot = val.coerce(&slot.var_type());
+            Ok(())
+        }
+    }
+}
+
+/// The type shared by the items, or the keys or values, of a literal that
+/// does not give one: the type of the first, or `float` if ints and floats
+/// are mixed.
+fn literal_type(items: &[Value]) -> Result<VarType, RuntimeError> {
+    items.iter()
+        .map(Value::var_type)
+        .reduce(|a, b| if a == VarType::IntType && b == VarType::FloatType { b } else { a })
+        .ok_or_else(|| RuntimeError::Unsupported("an empty literal without a type".to_string()))
+}
+
+/* SCOPES */
+
+/// Variables declared inside a block, or bound by an event handler.
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Frame {
+    pub vars: HashMap<String, (VarType, Value)>,
+}
+
+impl Environment for Frame {
+    fn set_var(&mut self, name: String, typ: VarType, val: Value) {
+        self.vars.insert(name, (typ, val));
+    }
+
+    fn get_var(&self, name: &str) -> Option<&(VarType, Value)> {
+        self.vars.get(name)
+    }
+}
+
+/// An event on its way to an actor. Sends are buffered until the handler
+/// that made them has finished, then delivered in the order they were made.
+#[derive(Debug)]
+pub struct Envelope {
+    pub target: ActorRef,
+    pub event: EventInstance,
+}
+
+/// Something the engine does once time has moved on: take an `on after`
+/// transition, unless its state has been exited since the timer was armed,
+/// or deliver an event sent with `after`.
+#[derive(Debug)]
+pub enum Delayed {
+    Timeout { actor: u32, path: Vec<String>, index: usize, entered: u64 },
+    Send(Envelope),
+}
+
+/// What a handler leaves behind for the engine once it has finished: the
+/// events it sent and the instances it spawned, which are started before
+/// any of the events are delivered, and what it wants done later along
+/// with the delay in milliseconds.
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Outbox {
+    pub sends: Vec<Envelope>,
+    pub spawned: Vec<Actor>,
+    pub delayed: Vec<(u64, Delayed)>,
+}
+
+/// Returns the path to every innermost active state below `root`, names
+/// outermost first. Without parallel states there is exactly one.
+pub fn active_paths(root: &State) -> Vec<Vec<String>> {
+    let subs = root.active_subs();
+    if subs.is_empty() {
+        return vec![vec![]];
+    }
+
+    subs.into_iter()
+        .flat_map(|name| active_paths(&root.subs[name]).into_iter().map(move |mut path| {
+            path.insert(0, name.clone());
+            path
+        }))
+        .collect()
+}
+
+/// Whether the state at `path` is part of the active configuration.
+pub fn is_active(root: &State, path: &[String]) -> bool {
+    let mut state = root;
+    for name in path {
+        if !state.active_subs().contains(&name) {
+            return false;
+        }
+        state = &state.subs[name];
+    }
+
+    true
+}
+
+pub fn state_at<'s>(root: &'s State, path: &[String]) -> &'s State {
+    path.iter().fold(root, |state, name| &state.subs[name])
+}
+
+pub fn state_at_mut<'s>(root: &'s mut State, path: &[String]) -> &'s mut State {
+    path.iter().fold(root, |state, name| state.subs.get_mut(name).unwrap())
+}
+
+/// Events, records, funcs and actor templates of every loaded unit,
+/// gathered when a unit is loaded so running code can reach them while the
+/// actors themselves are borrowed. `named` maps the name of each actor without
+/// parameters to the instance started for it, so the name can be used
+/// wherever a reference is expected.
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Globals {
+    pub events: HashMap<String, EventSignature>,
+    pub records: HashMap<String, RecordSignature>,
+    pub funcs: HashMap<String, Arc<FuncSignature>>,
+    pub actors: HashMap<String, Arc<Actor>>,
+    pub named: HashMap<String, ActorRef>,
+}
+
+/// What a statement asks of the block around it.
+pub enum Flow {
+    Next,
+    Return(Option<Value>),
+    Terminate,
+}
+
+/// Everything a block of statements can see while it runs: its own frames,
+/// then the states on `path` from the innermost outwards, then the actor.
+/// `sender` is whoever sent the event being handled, and `completed` the
+/// states whose sub-machines reached a final state during this step.
+pub struct Context<'a> {
+    pub actor: Option<&'a mut Actor>,
+    pub sender: Option<ActorRef>,
+    pub path: Vec<String>,
+    pub frames: Vec<Frame>,
+    pub globals: &'a Globals,
+    pub outbox: &'a mut Outbox,
+    pub completed: Vec<Vec<String>>,
+    pub depth: usize,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(globals: &'a Globals, outbox: &'a mut Outbox) -> Self {
+        Context { actor: None, sender: None, path: vec![], frames: vec![Frame::default()], globals, outbox, completed: vec![], depth: 0 }
+    }
+
+    /// Whether the actor has run `terminate`, after which nothing more of
+    /// the step it was taking is carried out.
+    pub fn terminated(&self) -> bool {
+        self.actor.as_ref().is_some_and(|actor| actor.terminated)
+    }
+
+    /// Calls a func in a fresh context that sees only its parameters and
+    /// locals, and returns whatever it returned.
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Option<Value>, RuntimeError> {
+        let globals = self.globals;
+        let func = globals.funcs.get(name)
+            .ok_or_else(|| RuntimeError::UnknownFunction(name.to_string()))?;
+
+        if func.params.len() != args.len() {
+            return Err(RuntimeError::ArgumentCount {
+                func: name.to_string(),
+                expected: func.params.len(),
+                found: args.len(),
+            });
+        }
+
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(RuntimeError::StackOverflow(name.to_string()));
+        }
+
+        let mut frame = Frame::default();
+        for ((param, typ), val) in func.params.iter().zip(args) {
+            frame.set_var(param.clone(), typ.clone(), val.coerce(typ));
+        }
+
+        let mut callee = Context::new(globals, self.outbox);
+        callee.frames = vec![frame];
+        callee.depth = self.depth + 1;
+
+        let returned = match callee.exec_block(&func.body)? {
+            Flow::Return(val) => val,
+            Flow::Next | Flow::Terminate => None,
+        };
+
+        match (&func.ret_type, returned) {
+            (Some(typ), Some(val)) => Ok(Some(val.coerce(typ))),
+            (Some(_), None) => Err(RuntimeError::MissingReturn(name.to_string())),
+            (None, _) => Ok(None),
+        }
+    }
+
+    /// Runs a call written in the program, which may also be to a built-in
+    /// operation or build a record.
+    fn eval_call(&mut self, name: &str, args: &[Spanned<ValueExpr>]) -> Result<Option<Value>, RuntimeError> {
+        if let Some(builtin) = BUILTINS.iter().find(|builtin| **builtin == name) {
+            return self.builtin(builtin, args);
+        }
+
+        let globals = self.globals;
+        if let Some(record) = globals.records.get(name) {
+            return self.build(record, args).map(Some);
+        }
+
+        let args = args.iter().map(|arg| self.eval(arg)).collect::<Result<_, _>>()?;
+        self.call(name, args)
+    }
+
+    fn build(&mut self, record: &RecordSignature, args: &[Spanned<ValueExpr>]) -> Result<Value, RuntimeError> {
+        if record.fields.len() != args.len() {
+            return Err(RuntimeError::ArgumentCount {
+                func: record.name.clone(),
+                expected: record.fields.len(),
+                found: args.len(),
+            });
+        }
+
+        let mut fields = vec![];
+        for ((field, typ), arg) in record.fields.iter().zip(args) {
+            fields.push((field.clone(), self.eval(arg)?.coerce(typ)));
+        }
+
+        Ok(Value::Record { name: record.name.clone(), fields })
+    }
+
+    fn builtin(&mut self, name: &'static str, args: &[Spanned<ValueExpr>]) -> Result<Option<Value>, RuntimeError> {
+        let expected = match (name, args.len()) {
+            ("len", 1) | ("insert", 2..=3) | ("remove", 2) => None,
+            ("len", _) => Some(1),
+            ("insert", found) => Some(if found < 2 { 2 } else { 3 }),
+            _ => Some(2),
+        };
+        if let Some(expected) = expected {
+            return Err(RuntimeError::ArgumentCount { func: name.to_string(), expected, found: args.len() });
+        }
+
+        if name == "len" {
+            return match self.eval(&args[0])? {
+                Value::List { items, .. } => Ok(Some(Value::Int(items.len() as i64))),
+                Value::Map { entries, .. } => Ok(Some(Value::Int(entries.len() as i64))),
+                Value::Str(s) => Ok(Some(Value::Int(s.chars().count() as i64))),
+                other => Err(RuntimeError::InvalidOperand { op: "len", operand: other.var_type() }),
+            };
+        }
+
+        let (var, keys) = self.place(&args[0])?;
+        let rest: Vec<Value> = args[1..].iter().map(|arg| self.eval(arg)).collect::<Result<_, _>>()?;
+        self.update(&var, &keys, |target| match (name, target, rest.as_slice()) {
+            ("insert", Value::List { elem, items }, [val]) => {
+                items.push(val.clone().coerce(elem));
+                Ok(None)
+            }
+            ("insert", Value::List { elem, items }, [index, val]) => {
+                let at = position(index, items.len(), true)?;
+                items.insert(at, val.clone().coerce(elem));
+                Ok(None)
+            }
+            ("insert", Value::Map { key: key_type, value, entries }, [key, val]) => {
+                map_insert(entries, key.clone().coerce(key_type), val.clone().coerce(value));
+                Ok(None)
+            }
+            ("remove", Value::List { items, .. }, [index]) => {
+                let at = position(index, items.len(), false)?;
+                Ok(Some(items.remove(at)))
+            }
+            ("remove", Value::Map { entries, .. }, [key]) => {
+                match entries.binary_search_by(|(k, _)| key_order(k, key)) {
+                    Ok(at) => Ok(Some(entries.remove(at).1)),
+                    Err(_) => Err(RuntimeError::MissingKey(key.to_string())),
+                }
+            }
+            (_, other, _) => Err(RuntimeError::InvalidOperand { op: name, operand: other.var_type() }),
+        })
+    }
+
+    /// Splits something that can be assigned to, such as `orders[0].qty`,
+    /// into the variable it starts from and the steps leading into it,
+    /// evaluating the indexes along the way.
+    fn place(&mut self, expr: &Spanned<ValueExpr>) -> Result<(String, Vec<Key>), RuntimeError> {
+        match &expr.node {
+            ValueExpr::Ident(name) => Ok((name.clone(), vec![])),
+            ValueExpr::IndexExpr { v, index } => {
+                let (var, mut keys) = self.place(v)?;
+                keys.push(Key::Index(self.eval(index)?));
+                Ok((var, keys))
+            }
+            ValueExpr::FieldExpr { v, field } => {
+                let (var, mut keys) = self.place(v)?;
+                keys.push(Key::Field(field.clone()));
+                Ok((var, keys))
+            }
+            _ => Err(RuntimeError::Unsupported("assigning to a value that is not a variable".to_string())),
+        }
+    }
+
+    /// Applies `change` to what `keys` lead to inside the variable `var`,
+    /// then stores the variable back.
+    fn update<T>(&mut self, var: &str, keys: &[Key], change: impl FnOnce(&mut Value) -> Result<T, RuntimeError>) -> Result<T, RuntimeError> {
+        let mut val = self.lookup(var)
+            .map(|(_, val)| val.clone())
+            .ok_or_else(|| RuntimeError::UnknownVariable(var.to_string()))?;
+
+        let mut target = &mut val;
+        for key in keys {
+            target = element(target, key)?;
+        }
+
+        let result = change(target)?;
+        self.assign(var, val)?;
+        Ok(result)
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&(VarType, Value)> {
+        if let Some(var) = self.frames.iter().rev().find_map(|frame| frame.get_var(name)) {
+            return Some(var);
+        }
+
+        let actor = self.actor.as_ref()?;
+        if let Some(root) = &actor.statemachine {
+            for depth in (0..=self.path.len()).rev() {
+                if let Some(var) = state_at(root, &self.path[..depth]).get_var(name) {
+                    return Some(var);
+                }
+            }
+        }
+
+        actor.get_var(name)
+    }
+
+    fn assign(&mut self, name: &str, val: Value) -> Result<(), RuntimeError> {
+        if let Some(frame) = self.frames.iter_mut().rev().find(|frame| frame.get_var(name).is_some()) {
+            let typ = frame.vars[name].0.clone();
+            let val = val.coerce(&typ);
+            frame.set_var(name.to_string(), typ, val);
+            return Ok(());
+        }
+
+        let Some(actor) = self.actor.as_mut() else {
+            return Err(RuntimeError::UnknownVariable(name.to_string()));
+        };
+
+        if let Some(root) = actor.statemachine.as_mut() {
+            for depth in (0..=self.path.len()).rev() {
+                let state = state_at_mut(root, &self.path[..depth]);
+                if let Some((typ, _)) = state.get_var(name) {
+                    let typ = typ.clone();
+                    let val = val.coerce(&typ);
+                    state.set_var(name.to_string(), typ, val);
+                    return Ok(());
+                }
+            }
+        }
+
+        match actor.get_var(name) {
+            Some((typ, _)) => {
+                let typ = typ.clone();
+                let val = val.coerce(&typ);
+                actor.set_var(name.to_string(), typ, val);
+                Ok(())
+            }
+            None => Err(RuntimeError::UnknownVariable(name.to_string())),
+        }
+    }
+
+    pub fn eval(&mut self, expr: &Spanned<ValueExpr>) -> Result<Value, RuntimeError> {
+        self.eval_node(&expr.node).map_err(|err| err.at(expr.span))
+    }
+
+    fn eval_node(&mut self, expr: &ValueExpr) -> Result<Value, RuntimeError> {
+        match expr {
+            ValueExpr::Bool(b) => Ok(Value::Bool(*b)),
+            ValueExpr::Int(i) => Ok(Value::Int(*i)),
+            ValueExpr::Float(f) => Ok(Value::Float(*f)),
+            ValueExpr::Str(s) => Ok(Value::Str(s.clone())),
+            ValueExpr::Ident(name) => self.lookup(name)
+                .map(|(_, val)| val.clone())
+                .or_else(|| self.globals.named.get(name).cloned().map(Value::ActorRef))
+                .ok_or_else(|| RuntimeError::UnknownVariable(name.clone())),
+            ValueExpr::AddExpr { l, r } => add(self.eval(l)?, self.eval(r)?),
+            ValueExpr::SubExpr { l, r } => arith("-", self.eval(l)?, self.eval(r)?, i64::checked_sub, |a, b| a - b),
+            ValueExpr::MulExpr { l, r } => arith("*", self.eval(l)?, self.eval(r)?, i64::checked_mul, |a, b| a * b),
+            ValueExpr::DivExpr { l, r } => divide(self.eval(l)?, self.eval(r)?),
+            ValueExpr::NotExpr { v } => match self.eval(v)? {
+                Value::Bool(b) => Ok(Value::Bool(!b)),
+                other => Err(RuntimeError::InvalidOperand { op: "!", operand: other.var_type() }),
+            },
+            ValueExpr::AndExpr { l, r } => match self.eval_bool("&&", l)? {
+                false => Ok(Value::Bool(false)),
+                true => self.eval_bool("&&", r).map(Value::Bool),
+            },
+            ValueExpr::OrExpr { l, r } => match self.eval_bool("||", l)? {
+                true => Ok(Value::Bool(true)),
+                false => self.eval_bool("||", r).map(Value::Bool),
+            },
+            ValueExpr::XorExpr { l, r } => Ok(Value::Bool(self.eval_bool("^^", l)? ^ self.eval_bool("^^", r)?)),
+            ValueExpr::EqExpr { l, r } => equals("==", self.eval(l)?, self.eval(r)?).map(Value::Bool),
+            ValueExpr::NeqExpr { l, r } => equals("!=", self.eval(l)?, self.eval(r)?).map(|eq| Value::Bool(!eq)),
+            ValueExpr::LeqExpr { l, r } => compare("<=", self.eval(l)?, self.eval(r)?, |o| o.is_le()),
+            ValueExpr::GeqExpr { l, r } => compare(">=", self.eval(l)?, self.eval(r)?, |o| o.is_ge()),
+            ValueExpr::LtExpr { l, r } => compare("<", self.eval(l)?, self.eval(r)?, |o| o.is_lt()),
+            ValueExpr::GtExpr { l, r } => compare(">", self.eval(l)?, self.eval(r)?, |o| o.is_gt()),
+            ValueExpr::FuncCallExpr { func_name, func_args } => self.eval_call(func_name, func_args)?
+                .ok_or_else(|| RuntimeError::VoidValue(func_name.clone())),
+            ValueExpr::SpawnExpr { actor_name, args } => self.spawn(actor_name, args).map(Value::ActorRef),
+            ValueExpr::SelfRef => self.actor.as_ref()
+                .map(|actor| Value::ActorRef(actor.reference()))
+                .ok_or(RuntimeError::NoSelf),
+            ValueExpr::Sender => self.sender.clone().map(Value::ActorRef).ok_or(RuntimeError::NoSender),
+            ValueExpr::ListExpr { elem, items } => {
+                let items: Vec<Value> = items.iter().map(|item| self.eval(item)).collect::<Result<_, _>>()?;
+                let elem = match elem {
+                    Some(elem) => elem.clone(),
+                    None => literal_type(&items)?,
+                };
+                let items = items.into_iter().map(|item| item.coerce(&elem)).collect();
+                Ok(Value::List { elem, items })
+            }
+            ValueExpr::MapExpr { types, entries } => {
+                let mut pairs = vec![];
+                for (key, val) in entries {
+                    pairs.push((self.eval(key)?, self.eval(val)?));
+                }
+                let (key, value) = match types {
+                    Some(types) => types.clone(),
+                    None => {
+                        let (keys, values): (Vec<_>, Vec<_>) = pairs.iter().cloned().unzip();
+                        (literal_type(&keys)?, literal_type(&values)?)
+                    }
+                };
+
+                let mut map = vec![];
+                for (k, v) in pairs {
+                    map_insert(&mut map, k.coerce(&key), v.coerce(&value));
+                }
+                Ok(Value::Map { key, value, entries: map })
+            }
+            ValueExpr::IndexExpr { v, index } => {
+                let mut val = self.eval(v)?;
+                let key = Key::Index(self.eval(index)?);
+                element(&mut val, &key).cloned()
+            }
+            ValueExpr::FieldExpr { v, field } => {
+                let mut val = self.eval(v)?;
+                element(&mut val, &Key::Field(field.clone())).cloned()
+            }
+            ValueExpr::VariantExpr { enum_name, variant } =>
+                Ok(Value::Enum { name: enum_name.clone(), variant: variant.clone() }),
+        }
+    }
+
+    /// Creates a new instance of an actor with its parameters bound to
+    /// `args`. The instance is started by the engine once the current
+    /// handler has finished.
+    fn spawn(&mut self, name: &str, args: &[Spanned<ValueExpr>]) -> Result<ActorRef, RuntimeError> {
+        let globals = self.globals;
+        let template = globals.actors.get(name)
+            .ok_or_else(|| RuntimeError::UnknownActor(name.to_string()))?;
+
+        if template.params.len() != args.len() {
+            return Err(RuntimeError::ArgumentCount {
+                func: name.to_string(),
+                expected: template.params.len(),
+                found: args.len(),
+            });
+        }
+
+        let mut actor = template.instantiate();
+        for ((param, typ), arg) in template.params.iter().zip(args) {
+            let val = self.eval(arg)?.coerce(typ);
+            actor.set_var(param.clone(), typ.clone(), val);
+        }
+
+        let reference = actor.reference();
+        self.outbox.spawned.push(actor);
+        Ok(reference)
+    }
+
+    fn eval_bool(&mut self, op: &'static str, expr: &Spanned<ValueExpr>) -> Result<bool, RuntimeError> {
+        match self.eval(expr)? {
+            Value::Bool(b) => Ok(b),
+            other => Err(RuntimeError::InvalidOperand { op, operand: other.var_type() }),
+        }
+    }
+
+    fn eval_condition(&mut self, expr: &Spanned<ValueExpr>) -> Result<bool, RuntimeError> {
+        match self.eval(expr)? {
+            Value::Bool(b) => Ok(b),
+            other => Err(RuntimeError::NonBoolCondition(other.var_type())),
+        }
+    }
+
+    pub fn exec_block(&mut self, block: &[Spanned<ControlFlowExpr>]) -> Result<Flow, RuntimeError> {
+        self.frames.push(Frame::default());
+        let mut result = Ok(Flow::Next);
+        for stmt in block {
+            result = self.exec(stmt).map_err(|err| err.at(stmt.span));
+            if !matches!(result, Ok(Flow::Next)) {
+                break;
+            }
+        }
+        self.frames.pop();
+        result
+    }
+
+    fn exec(&mut self, stmt: &Spanned<ControlFlowExpr>) -> Result<Flow, RuntimeError> {
+        match &stmt.node {
+            ControlFlowExpr::VarDecl { var_name, var_type, initial } => {
+                if let Some(initial) = initial {
+                    let val = self.eval(initial)?.coerce(var_type);
+                    self.frames.last_mut().unwrap().set_var(var_name.clone(), var_type.clone(), val);
+                }
+                Ok(Flow::Next)
+            }
+
+            ControlFlowExpr::SendStatement { target, event, delay } => {
+                let ValueExpr::FuncCallExpr { func_name, func_args } = &event.node else {
+                    return Err(RuntimeError::Unsupported("sending a non-event value".to_string()));
+                };
+
+                let signature = self.globals.events.get(func_name)
+                    .ok_or_else(|| RuntimeError::UnknownEvent(func_name.clone()))?
+                    .clone();
+                let target = match self.eval(target)? {
+                    Value::ActorRef(target) => target,
+                    other => return Err(RuntimeError::InvalidOperand { op: "!", operand: other.var_type() }),
+                };
+                let params = func_args.iter().map(|arg| self.eval(arg)).collect::<Result<_, _>>()?;
+                let sender = self.actor.as_ref().map(|actor| actor.reference());
+                let envelope = Envelope { target, event: EventInstance { signature, params, sender } };
+                match delay {
+                    Some(delay) => self.outbox.delayed.push((*delay, Delayed::Send(envelope))),
+                    None => self.outbox.sends.push(envelope),
+                }
+                Ok(Flow::Next)
+            }
+
+            ControlFlowExpr::AssignStatement { target, val_expr } => {
+                let val = self.eval(val_expr)?;
+                let (var, mut keys) = self.place(target)?;
+                match keys.pop() {
+                    Some(key) => self.update(&var, &keys, |container| store(container, key, val))?,
+                    None => self.assign(&var, val)?,
+                }
+                Ok(Flow::Next)
+            }
+
+            ControlFlowExpr::FuncCallStatement(call) => {
+                let ValueExpr::FuncCallExpr { func_name, func_args } = &call.node else {
+                    return Err(RuntimeError::Unsupported("calling a non-func value".to_string()));
+                };
+
+                self.eval_call(func_name, func_args)?;
+                Ok(Flow::Next)
+            }
+
+            ControlFlowExpr::ReturnStatement(val_expr) => {
+                let val = val_expr.as_ref().map(|expr| self.eval(expr)).transpose()?;
+                Ok(Flow::Return(val))
+            }
+
+            ControlFlowExpr::TerminateStatement => {
+                self.actor.as_mut().ok_or(RuntimeError::NoSelf)?.terminated = true;
+                Ok(Flow::Terminate)
+            }
+
+            ControlFlowExpr::IfStatement { branches, otherwise } => {
+                for (condition, body) in branches {
+                    if self.eval_condition(condition)? {
+                        return self.exec_block(body);
+                    }
+                }
+                match otherwise {
+                    Some(otherwise) => self.exec_block(otherwise),
+                    None => Ok(Flow::Next),
+                }
+            }
+
+            ControlFlowExpr::WhileStatement { condition, body } => {
+                let mut iterations = 0;
+                while self.eval_condition(condition)? {
+                    if iterations == MAX_LOOP_ITERATIONS {
+                        return Err(RuntimeError::LoopLimit);
+                    }
+                    iterations += 1;
+
+                    match self.exec_block(body)? {
+                        Flow::Next => {}
+                        flow => return Ok(flow),
+                    }
+                }
+                Ok(Flow::Next)
+            }
+
+            ControlFlowExpr::MatchStatement { scrutinee, arms } => {
+                let val = self.eval(scrutinee)?;
+                let arm = arms.iter().find(|(pattern, _)| match (pattern, &val) {
+                    (MatchPattern::Int(i), Value::Int(v)) => i == v,
+                    (MatchPattern::Str(s), Value::Str(v)) => s == v,
+                    (MatchPattern::Variant { enum_name, variant }, Value::Enum { name, variant: v }) =>
+                        enum_name == name && variant == v,
+                    (MatchPattern::Wildcard, _) => true,
+                    _ => false,
+                });
+                match arm {
+                    Some((_, body)) => self.exec_block(body),
+                    None => Ok(Flow::Next),
+                }
+            }
+        }
+    }
+}
+
+/// Evaluates the variable declarations of an actor and of every state in its
+/// state machine, in declaration order, so initializers can refer to
+/// variables declared before them or in enclosing scopes.
+fn init_vars(ctx: &mut Context) -> Result<(), RuntimeError> {
+    let decls = ctx.actor.as_ref().unwrap().decls.clone();
+    for (name, typ, initial) in decls {
+        let val = ctx.eval(&initial)?.coerce(&typ);
+        ctx.actor.as_mut().unwrap().set_var(name, typ, val);
+    }
+
+    if ctx.actor.as_ref().unwrap().statemachine.is_some() {
+        init_state_vars(ctx, vec![])?;
+    }
+
+    Ok(())
+}
+
+fn init_state_vars(ctx: &mut Context, path: Vec<String>) -> Result<(), RuntimeError> {
+    let state = state_at(ctx.actor.as_ref().unwrap().statemachine.as_ref().unwrap(), &path);
+    let (decls, order) = (state.decls.clone(), state.order.clone());
+
+    ctx.path = path.clone();
+    for (name, typ, initial) in decls {
+        let val = ctx.eval(&initial)?.coerce(&typ);
+        let root = ctx.actor.as_mut().unwrap().statemachine.as_mut().unwrap();
+        state_at_mut(root, &path).set_var(name, typ, val);
+    }
+
+    for sub in order {
+        let mut sub_path = path.clone();
+        sub_path.push(sub);
+        init_state_vars(ctx, sub_path)?;
+    }
+
+    Ok(())
+}
+
+/* DISPATCH */
+
+/// Where a selected handler was declared: in one of the active states,
+/// identified by its path below the root, or on the actor itself.
+enum Owner {
+    State(Vec<String>),
+    Actor,
+}
+
+/// A handler chosen to run for an event: the `index`th of the handlers
+/// for the event declared at `owner`, with the event's parameters bound.
+struct Selected {
+    owner: Owner,
+    index: usize,
+    frame: Frame,
+}
+
+fn handlers<'s>(actor: &'s Actor, owner: &Owner, event: &str) -> &'s [Transition] {
+    let transitions = match owner {
+        Owner::State(path) => &state_at(actor.statemachine.as_ref().unwrap(), path).transitions,
+        Owner::Actor => &actor.transitions,
+    };
+
+    transitions.get_vec(event).map(Vec::as_slice).unwrap_or_default()
+}
+
+fn bind(trans: &Transition, event: &EventInstance) -> Frame {
+    let mut frame = Frame::default();
+    let types = event.signature.params.iter();
+    for ((name, typ), val) in trans.bound_vars.iter().zip(types).zip(&event.params) {
+        frame.set_var(name.clone(), typ.clone(), val.clone().coerce(typ));
+    }
+
+    frame
+}
+
+fn guards_pass(ctx: &mut Context, conditions: &[Spanned<ValueExpr>]) -> Result<bool, RuntimeError> {
+    for cond in conditions {
+        if !ctx.eval_condition(cond)? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns the first handler for `event` declared at `owner` whose guards
+/// all hold. Handlers are tried in the order they were declared, except
+/// that an `else` handler is only tried once all the others have failed.
+fn first_enabled(ctx: &mut Context, owner: Owner, event: &EventInstance) -> Result<Option<Selected>, RuntimeError> {
+    let name = &event.signature.name;
+    let all = handlers(ctx.actor.as_ref().unwrap(), &owner, name);
+    let (otherwise, guarded): (Vec<usize>, Vec<usize>) = (0..all.len()).partition(|&index| all[index].otherwise);
+
+    for index in guarded.into_iter().chain(otherwise) {
+        let trans = &handlers(ctx.actor.as_ref().unwrap(), &owner, name)[index];
+        let (frame, conditions) = (bind(trans, event), trans.conditions.clone());
+
+        ctx.path = match &owner { Owner::State(path) => path.clone(), Owner::Actor => vec![] };
+        ctx.frames = vec![frame];
+        if guards_pass(ctx, &conditions)? {
+            return Ok(Some(Selected { owner, index, frame: ctx.frames.pop().unwrap() }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Chooses the handlers for `event` among the active states below `path`.
+/// The innermost state with an enabled handler wins, and each region of a
+/// parallel state chooses on its own; the state at `path` is only asked
+/// when nothing below it handles the event.
+fn select_handlers(ctx: &mut Context, path: Vec<String>, event: &EventInstance) -> Result<Vec<Selected>, RuntimeError> {
+    let root = ctx.actor.as_ref().unwrap().statemachine.as_ref().unwrap();
+    let subs: Vec<String> = state_at(root, &path).active_subs().into_iter().cloned().collect();
+
+    let mut selected = vec![];
+    for sub in subs {
+        let mut sub_path = path.clone();
+        sub_path.push(sub);
+        selected.extend(select_handlers(ctx, sub_path, event)?);
+    }
+
+    if selected.is_empty() {
+        selected.extend(first_enabled(ctx, Owner::State(path), event)?);
+    }
+
+    Ok(selected)
+}
+
+/// Makes the state at `path` the active sub-state of its parent.
+fn select(ctx: &mut Context, path: &[String]) {
+    let Some((name, parent)) = path.split_last() else { return; };
+    let root = ctx.actor.as_mut().unwrap().statemachine.as_mut().unwrap();
+    state_at_mut(root, parent).at = name.clone();
+}
+
+/// Enters the state at `path`, which its parent has already selected: runs
+/// its entry block, then enters the sub-states leading down to `rest`.
+fn enter(ctx: &mut Context, path: Vec<String>, rest: &[String], resume: bool) -> Result<(), RuntimeError> {
+    arm_timers(ctx, &path);
+    run_handler(ctx, &path, "_ENTRY")?;
+    if ctx.terminated() {
+        return Ok(());
+    }
+
+    let root = ctx.actor.as_ref().unwrap().statemachine.as_ref().unwrap();
+    if let (StateKind::Final, Some((_, parent))) = (state_at(root, &path).kind, path.split_last()) {
+        ctx.completed.push(parent.to_vec());
+    }
+
+    enter_subs(ctx, path, rest, resume)
+}
+
+/// Enters the sub-states of the state at `path` leading down to `rest`.
+/// Past the end of `rest` the defaults are entered instead: every region
+/// of a parallel state in declaration order, otherwise the sub-state that
+/// was last active if the state has history, or its `initial` one.
+/// `resume` is set for default entries below a state with deep history,
+/// where every state resumes as if it had history of its own.
+fn enter_subs(ctx: &mut Context, path: Vec<String>, rest: &[String], resume: bool) -> Result<(), RuntimeError> {
+    let root = ctx.actor.as_ref().unwrap().statemachine.as_ref().unwrap();
+    let state = state_at(root, &path);
+    let resume_subs = resume || state.history == Some(History::Deep);
+    let default = match &state.last {
+        Some(last) if resume || state.history.is_some() => last,
+        _ => &state.initial,
+    };
+
+    // Each sub-state to enter, what to enter below it, and whether it is a
+    // default entry that deep history applies to.
+    let next: Vec<(String, &[String], bool)> = match (state.kind, rest.split_first()) {
+        (StateKind::Parallel, next) => state.order.iter()
+            .map(|region| match next {
+                Some((name, rest)) if name == region => (region.clone(), rest, false),
+                _ => (region.clone(), &[][..], resume_subs),
+            })
+            .collect(),
+        (_, Some((name, rest))) => vec![(name.clone(), rest, false)],
+        (_, None) if state.subs.contains_key(default) => vec![(default.clone(), &[][..], resume_subs)],
+        (_, None) => vec![],
+    };
+
+    for (name, rest, resume) in next {
+        let mut sub_path = path.clone();
+        sub_path.push(name);
+        select(ctx, &sub_path);
+        enter(ctx, sub_path, rest, resume)?;
+        if ctx.terminated() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts the clock on every `on after` handler of the state at `path`.
+fn arm_timers(ctx: &mut Context, path: &[String]) {
+    let actor = ctx.actor.as_mut().unwrap();
+    let id = actor.id;
+    let state = state_at_mut(actor.statemachine.as_mut().unwrap(), path);
+    state.entered += 1;
+
+    for (index, trans) in state.transitions.get_vec("_AFTER").into_iter().flatten().enumerate() {
+        let timeout = Delayed::Timeout { actor: id, path: path.to_vec(), index, entered: state.entered };
+        ctx.outbox.delayed.push((trans.after.unwrap_or_default(), timeout));
+    }
+}
+
+/// Exits the state at `path` after its active sub-states, innermost first
+/// and the regions of a parallel state in reverse declaration order. Each
+/// state remembers its active sub-state for history.
+fn exit(ctx: &mut Context, path: Vec<String>) -> Result<(), RuntimeError> {
+    let root = ctx.actor.as_mut().unwrap().statemachine.as_mut().unwrap();
+    let state = state_at_mut(root, &path);
+    if state.kind != StateKind::Parallel && state.subs.contains_key(&state.at) {
+        state.last = Some(state.at.clone());
+    }
+    let subs: Vec<String> = state.active_subs().into_iter().cloned().collect();
+
+    for sub in subs.into_iter().rev() {
+        let mut sub_path = path.clone();
+        sub_path.push(sub);
+        exit(ctx, sub_path)?;
+        if ctx.terminated() {
+            return Ok(());
+        }
+    }
+
+    run_handler(ctx, &path, "_EXIT")
+}
+
+/// Brings an actor to life: initializes its variables, runs its `entry`
+/// block, then enters its state machine from the top.
+pub fn start(actor: &mut Actor, globals: &Globals, outbox: &mut Outbox) -> Result<(), RuntimeError> {
+    let mut ctx = Context::new(globals, outbox);
+    ctx.actor = Some(actor);
+    init_vars(&mut ctx)?;
+
+    let entry = ctx.actor.as_ref().unwrap().transitions.get("_ENTRY").map(|handler| handler.body.clone());
+    if let Some(body) = entry {
+        ctx.path = vec![];
+        ctx.exec_block(&body)?;
+    }
+
+    if ctx.actor.as_ref().unwrap().statemachine.is_some() && !ctx.terminated() {
+        enter(&mut ctx, vec![], &[], false)?;
+        complete(&mut ctx)?;
+    }
+
+    Ok(())
+}
+
+fn run_handler(ctx: &mut Context, path: &[String], name: &str) -> Result<(), RuntimeError> {
+    let root = ctx.actor.as_ref().and_then(|actor| actor.statemachine.as_ref()).unwrap();
+    let Some(handler) = state_at(root, path).transitions.get(name) else { return Ok(()); };
+    let body = handler.body.clone();
+
+    ctx.path = path.to_vec();
+    ctx.frames = vec![Frame::default()];
+    ctx.exec_block(&body).map(|_| ())
+}
+
+/// Runs a selected handler. An internal one runs in place. An external
+/// `goto` exits the active states up to the least common ancestor of the
+/// handler's state and the target, runs the handler, then enters the
+/// states down to the target and its defaults, so a `goto` to the
+/// handler's own state leaves and re-enters it. A local `goto` between a
+/// state and one it contains leaves the outer state alone and only exits
+/// and enters what lies below it.
+fn fire(ctx: &mut Context, selected: Selected, event: &str) -> Result<(), RuntimeError> {
+    let Selected { owner, index, frame } = selected;
+    let actor = ctx.actor.as_ref().unwrap();
+    let trans = &handlers(actor, &owner, event)[index];
+    let (target, body) = (trans.target.clone(), trans.body.clone());
+
+    let source = match owner {
+        Owner::State(path) => path,
+        Owner::Actor => {
+            ctx.path = vec![];
+            ctx.frames = vec![frame];
+            return ctx.exec_block(&body).map(|_| ());
+        }
+    };
+
+    // A transition fired in another region may already have left the state
+    // this handler was chosen in.
+    let root = actor.statemachine.as_ref().unwrap();
+    if !is_active(root, &source) {
+        return Ok(());
+    }
+
+    let (target, local) = match target {
+        Target::Internal => {
+            ctx.path = source;
+            ctx.frames = vec![frame];
+            return ctx.exec_block(&body).map(|_| ());
+        }
+        Target::External(target) => (target, false),
+        Target::Local(target) => (target, true),
+    };
+
+    let mut target = root.path_to(&target).ok_or(RuntimeError::UnknownState(target))?;
+    let mut lca = common_ancestor(root, &source, &target, local);
+    exit_below(ctx, &target[..lca])?;
+    if ctx.terminated() {
+        return Ok(());
+    }
+
+    ctx.path = source;
+    ctx.frames = vec![frame];
+    if let Flow::Terminate = ctx.exec_block(&body)? {
+        return Ok(());
+    }
+
+    // A choice passes the transition on to the target of one of its
+    // branches, which may lie outside the states left so far.
+    loop {
+        let root = ctx.actor.as_ref().unwrap().statemachine.as_ref().unwrap();
+        if state_at(root, &target).kind != StateKind::Choice {
+            break;
+        }
+
+        let next = choose(ctx, &target)?;
+        let root = ctx.actor.as_ref().unwrap().statemachine.as_ref().unwrap();
+        let next = root.path_to(&next).ok_or(RuntimeError::UnknownState(next))?;
+        let shared = common_ancestor(root, &target, &next, false);
+        if shared < lca {
+            exit_below(ctx, &next[..shared])?;
+            lca = shared;
+        }
+        target = next;
+    }
+
+    // A local transition to a state containing its source, or to the
+    // source itself, only re-enters what lies below it.
+    if lca == target.len() {
+        return enter_subs(ctx, target, &[], false);
+    }
+
+    let entered = target[..=lca].to_vec();
+    select(ctx, &entered);
+    enter(ctx, entered, &target[lca + 1..], false)
+}
+
+/// How many states `source` and `target` share before the transition
+/// between them leaves the rest. When one contains the other, an external
+/// transition leaves and re-enters the outer one too, so the ancestor is
+/// taken one level higher, as it is when the ancestor is parallel and its
+/// regions cannot be left one at a time.
+fn common_ancestor(root: &State, source: &[String], target: &[String], local: bool) -> usize {
+    let shared = source.iter().zip(target).take_while(|(a, b)| a == b).count();
+    let nested = shared == source.len() || shared == target.len();
+    let mut lca = if nested && !local { shared.saturating_sub(1) } else { shared };
+    while lca > 0 && state_at(root, &target[..lca]).kind == StateKind::Parallel {
+        lca -= 1;
+    }
+
+    lca
+}
+
+/// Exits the active sub-state of the state at `path`, which is left with
+/// none until the transition under way selects the next one.
+fn exit_below(ctx: &mut Context, path: &[String]) -> Result<(), RuntimeError> {
+    let root = ctx.actor.as_ref().unwrap().statemachine.as_ref().unwrap();
+    let ancestor = state_at(root, path);
+    if !ancestor.subs.contains_key(&ancestor.at) {
+        return Ok(());
+    }
+
+    let mut left = path.to_vec();
+    left.push(ancestor.at.clone());
+    exit(ctx, left)?;
+
+    let root = ctx.actor.as_mut().unwrap().statemachine.as_mut().unwrap();
+    let ancestor = state_at_mut(root, path);
+    ancestor.last = Some(std::mem::take(&mut ancestor.at));
+    Ok(())
+}
+
+/// The event handlers such as `_CHOICE` and `_DONE` are selected by, which
+/// carries no parameters.
+fn pseudo_event(name: &str) -> EventInstance {
+    EventInstance {
+        signature: EventSignature { name: name.to_string(), ..EventSignature::default() },
+        ..EventInstance::default()
+    }
+}
+
+/// Picks the branch of the choice at `path` to take and returns its target.
+/// Branches are tried in the order they were declared, `else` last, and
+/// see whatever the transition's handler has done.
+fn choose(ctx: &mut Context, path: &[String]) -> Result<String, RuntimeError> {
+    let owner = Owner::State(path.to_vec());
+    let Some(Selected { owner, index, .. }) = first_enabled(ctx, owner, &pseudo_event("_CHOICE"))? else {
+        return Err(RuntimeError::NoBranch(path.last().cloned().unwrap_or_default()));
+    };
+
+    let target = &handlers(ctx.actor.as_ref().unwrap(), &owner, "_CHOICE")[index].target;
+    Ok(target.state().unwrap_or_default().to_string())
+}
+
+/// Takes the `index`th `on after` transition of the state at `path` if the
+/// state is still in the visit `entered` that armed it and its guards hold.
+/// Returns whether the transition was taken.
+pub fn timeout(actor: &mut Actor, path: &[String], index: usize, entered: u64, globals: &Globals, outbox: &mut Outbox) -> Result<bool, RuntimeError> {
+    let Some(root) = actor.statemachine.as_ref() else { return Ok(false); };
+    if !is_active(root, path) || state_at(root, path).entered != entered {
+        return Ok(false);
+    }
+
+    let conditions = state_at(root, path).transitions.get_vec("_AFTER").unwrap()[index].conditions.clone();
+    let mut ctx = Context::new(globals, outbox);
+    ctx.actor = Some(actor);
+    ctx.path = path.to_vec();
+    if !guards_pass(&mut ctx, &conditions)? {
+        return Ok(false);
+    }
+
+    let selected = Selected { owner: Owner::State(path.to_vec()), index, frame: Frame::default() };
+    fire(&mut ctx, selected, "_AFTER")?;
+    complete(&mut ctx)?;
+    Ok(true)
+}
+
+/// Whether the state at `path` has finished: its active sub-state is final,
+/// or, for a parallel state, every one of its regions has finished.
+fn is_done(root: &State, path: &[String]) -> bool {
+    let state = state_at(root, path);
+    match state.kind {
+        StateKind::Parallel => state.order.iter().all(|region| {
+            let mut region_path = path.to_vec();
+            region_path.push(region.clone());
+            is_done(root, &region_path)
+        }),
+        _ => state.subs.get(&state.at).is_some_and(|sub| sub.kind == StateKind::Final),
+    }
+}
+
+/// Takes the `on done` transitions of the states that reached a final
+/// sub-state during the step, in the order they got there. A region that
+/// finishes also finishes its parallel state once the other regions have.
+fn complete(ctx: &mut Context) -> Result<(), RuntimeError> {
+    while !ctx.completed.is_empty() && !ctx.terminated() {
+        let path = ctx.completed.remove(0);
+        let root = ctx.actor.as_ref().unwrap().statemachine.as_ref().unwrap();
+
+        // A transition taken since may have left the final state again.
+        if !is_active(root, &path) || !is_done(root, &path) {
+            continue;
+        }
+
+        if let Some((_, parent)) = path.split_last() {
+            let parallel = state_at(root, parent).kind == StateKind::Parallel;
+            if parallel && is_done(root, parent) && !ctx.completed.iter().any(|done| done == parent) {
+                ctx.completed.push(parent.to_vec());
+            }
+        }
+
+        if let Some(selected) = first_enabled(ctx, Owner::State(path), &pseudo_event("_DONE"))? {
+            fire(ctx, selected, "_DONE")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Offers `event` to the active states, innermost first, then to the actor,
+/// so an event a state does not handle bubbles up to its parents. Every
+/// region of a parallel state gets the event and may handle it. Returns
+/// whether the event was handled.
+pub fn dispatch(actor: &mut Actor, event: EventInstance, globals: &Globals, outbox: &mut Outbox) -> Result<bool, RuntimeError> {
+    let mut ctx = Context::new(globals, outbox);
+    let has_statemachine = actor.statemachine.is_some();
+    ctx.actor = Some(actor);
+    ctx.sender = event.sender.clone();
+
+    let mut selected = vec![];
+    if has_statemachine {
+        selected = select_handlers(&mut ctx, vec![], &event)?;
+    }
+    if selected.is_empty() {
+        selected.extend(first_enabled(&mut ctx, Owner::Actor, &event)?);
+    }
+
+    let handled = !selected.is_empty();
+    for selected in selected {
+        if ctx.terminated() {
+            break;
+        }
+        fire(&mut ctx, selected, &event.signature.name)?;
+    }
+
+    complete(&mut ctx)?;
+    Ok(handled)
+}
+
+/* ENGINE */
+
+/// What happened during a run of the program.
+#[derive(Debug)]
+#[derive(Default)]
+pub struct RunReport {
+    pub events_processed: usize,
+    pub final_states: BTreeMap<String, Vec<Vec<String>>>,
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} event(s) processed", self.events_processed)?;
+        for (actor, states) in &self.final_states {
+            let paths: Vec<_> = states.iter().map(|path| path.join(".")).collect();
+            writeln!(f, "{}: {}", actor, paths.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl EvalEngine {
+    /// Gathers the events, records, funcs and actors of every loaded unit
+    /// into `globals`.
+    pub(crate) fn link(&mut self) {
+        let mut globals = Globals::default();
+        for unit in self.units.iter_all().flat_map(|(_, units)| units.iter()) {
+            for (name, event) in &unit.events {
+                globals.events.insert(name.clone(), event.clone());
+            }
+
+            for (name, record) in &unit.records {
+                globals.records.insert(name.clone(), record.clone());
+            }
+
+            for (name, func) in &unit.funcs {
+                globals.funcs.insert(name.clone(), func.clone());
+            }
+
+            for (name, actor) in &unit.actors {
+                globals.actors.insert(name.clone(), actor.clone());
+            }
+        }
+
+        globals.named = std::mem::take(&mut self.globals.named);
+        self.globals = globals;
+    }
+
+    /// Finds the line of source a span points at, in whichever loaded file
+    /// it came from.
+    pub fn locate(&self, span: Span) -> Option<Location> {
+        self.units.iter_all()
+            .flat_map(|(_, units)| units.iter())
+            .find(|unit| unit.source.id == span.file)
+            .map(|unit| unit.source.locate(span))
+    }
+
+    pub(crate) fn fail(&self, error: RuntimeError) -> ProteusError {
+        ProteusError::runtime(error, |span| self.locate(span))
+    }
+
+    /// How an instance is shown to people: by its actor's name if it is the
+    /// default instance, otherwise by name and id, e.g. `Lights#7`.
+    pub fn label(&self, actor: &Actor) -> String {
+        match self.globals.named.get(&actor.name) {
+            Some(named) if named.id == actor.id => actor.name.clone(),
+            _ => actor.reference().to_string(),
+        }
+    }
+
+    /// Starts the instances a handler spawned, along with whatever those
+    /// spawn in turn, then delivers every event that was sent. Events for an
+    /// instance that has terminated are dropped.
+    fn settle(&mut self, mut outbox: Outbox) -> Result<(), RuntimeError> {
+        while !outbox.spawned.is_empty() {
+            for mut actor in std::mem::take(&mut outbox.spawned) {
+                start(&mut actor, &self.globals, &mut outbox)?;
+                if !actor.terminated {
+                    self.instances.insert(actor.id, actor);
+                }
+            }
+        }
+
+        for Envelope { target, event } in outbox.sends {
+            if let Some(instance) = self.instances.get_mut(&target.id) {
+                instance.push(event);
+                self.scheduler.ready(target.id);
+            }
+        }
+
+        self.arm(outbox.delayed);
+        Ok(())
+    }
+
+    /// Turns what handlers want done later into timers, counting their
+    /// delays from now.
+    pub(crate) fn arm(&mut self, delayed: Vec<(u64, Delayed)>) {
+        let now = self.clock.now();
+        for (delay, delayed) in delayed {
+            self.timers.insert((now + delay, self.timer_seq), delayed);
+            self.timer_seq += 1;
+        }
+    }
+
+    /// Drops the earliest pending timers while they belong to `on after`
+    /// transitions whose state has been exited since they were armed, so
+    /// the clock never moves on for a timer that would do nothing.
+    fn drop_stale_timers(&mut self) {
+        while let Some((&key, Delayed::Timeout { actor, path, entered, .. })) = self.timers.first_key_value() {
+            let live = self.instances.get(actor)
+                .and_then(|instance| instance.statemachine.as_ref())
+                .is_some_and(|root| is_active(root, path) && state_at(root, path).entered == *entered);
+            if live {
+                break;
+            }
+            self.timers.remove(&key);
+        }
+    }
+
+    /// When the earliest pending timer is due, if any.
+    pub fn next_timer(&mut self) -> Option<u64> {
+        self.drop_stale_timers();
+        self.timers.keys().next().map(|(due, _)| *due)
+    }
+
+    /// Moves the clock on to the earliest pending timer and fires it.
+    /// Timers due at the same time fire in the order they were armed.
+    pub(crate) fn fire_timer(&mut self) -> Result<(), ProteusError> {
+        self.drop_stale_timers();
+        let Some(((due, _), delayed)) = self.timers.pop_first() else { return Ok(()); };
+        self.clock.advance_to(due);
+
+        let mut outbox = Outbox::default();
+        match delayed {
+            Delayed::Send(envelope) => outbox.sends.push(envelope),
+            Delayed::Timeout { actor, path, index, entered } => {
+                if let Some(instance) = self.instances.get_mut(&actor) {
+                    let result = timeout(instance, &path, index, entered, &self.globals, &mut outbox);
+                    for _ in 0..instance.unpark() {
+                        self.scheduler.ready(actor);
+                    }
+                    if instance.terminated {
+                        self.instances.remove(&actor);
+                    }
+                    result.map_err(|err| self.fail(err))?;
+                }
+            }
+        }
+
+        self.settle(outbox).map_err(|err| self.fail(err))
+    }
+
+    /// Lets an instance handle the next event in its inbox, then delivers
+    /// whatever it sent. Returns false if every waiting event was deferred.
+    pub(crate) fn handle_next(&mut self, actor: u32) -> Result<bool, ProteusError> {
+        let mut outbox = Outbox::default();
+        let EvalEngine { instances, globals, scheduler, .. } = self;
+        let Some(instance) = instances.get_mut(&actor) else { return Ok(false); };
+        let Some(event) = instance.poll() else { return Ok(false); };
+
+        let result = dispatch(instance, event, globals, &mut outbox);
+        for _ in 0..instance.unpark() {
+            scheduler.ready(actor);
+        }
+        if instance.terminated {
+            instances.remove(&actor);
+        }
+        result.map_err(|err| self.fail(err))?;
+
+        self.settle(outbox).map_err(|err| self.fail(err))?;
+        Ok(true)
+    }
+
+    /// Calls a top-level func with the given arguments and returns its result.
+    pub fn call(&mut self, func: &str, args: Vec<Value>) -> Result<Option<Value>, ProteusError> {
+        let mut outbox = Outbox::default();
+        let returned = Context::new(&self.globals, &mut outbox).call(func, args)
+            .map_err(|err| self.fail(err))?;
+        self.settle(outbox).map_err(|err| self.fail(err))?;
+        Ok(returned)
+    }
+
+    /// Creates the default instance of every actor without parameters and
+    /// starts them in the order they were declared.
+    pub fn start(&mut self) -> Result<(), ProteusError> {
+        let mut templates: Vec<_> = self.globals.actors.values()
+            .filter(|actor| actor.params.is_empty() && !self.globals.named.contains_key(&actor.name))
+            .cloned()
+            .collect();
+        templates.sort_by_key(|actor| actor.id);
+
+        let mut outbox = Outbox::default();
+        for template in templates {
+            let actor = template.instantiate();
+            self.globals.named.insert(actor.name.clone(), actor.reference());
+            outbox.spawned.push(actor);
+        }
+
+        self.settle(outbox).map_err(|err| self.fail(err))
+    }
+
+    /// Starts every actor, runs `func main()`, then dispatches events and
+    /// fires timers as far as `limit` allows. A model whose timers keep
+    /// re-arming never runs out of things to do; give it a `Limit::Time`.
+    pub fn run_main(&mut self, limit: Limit) -> Result<RunReport, ProteusError> {
+        self.run_main_with(|engine| engine.run_until(limit))
+    }
+
+    /// Like `run_main`, but handles events on a pool of `workers` threads
+    /// until the program is quiescent. The scheduling policy is not used.
+    pub fn run_main_threaded(&mut self, workers: usize) -> Result<RunReport, ProteusError> {
+        self.run_main_with(|engine| engine.until_quiescent_threaded(workers))
+    }
+
+    fn run_main_with(&mut self, run: impl FnOnce(&mut Self) -> Result<usize, ProteusError>) -> Result<RunReport, ProteusError> {
+        if !self.globals.funcs.contains_key("main") {
+            return Err(RuntimeError::UnknownFunction("main".to_string()).into());
+        }
+
+        self.start()?;
+        self.call("main", vec![])?;
+
+        let mut report = RunReport { events_processed: run(self)?, ..RunReport::default() };
+
+        for actor in self.instances.values() {
+            let states = actor.statemachine.as_ref().map(active_paths).unwrap_or_default();
+            report.final_states.insert(self.label(actor), states);
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ProteusError;
+    use crate::eval::EvalEngine;
+    use crate::scheduler::Limit;
+    use crate::testing::{compile, run, states, var};
+
+    #[test]
+    fn lights_toggle() {
+        let mut engine = EvalEngine::default();
+        engine.load_from_file("tests/lights_toggle.pro").unwrap();
+        engine.compile().unwrap();
+        let report = engine.run_main(Limit::Quiescent).unwrap();
+
+        assert_eq!(report.events_processed, 3);
+        assert_eq!(report.to_string(), "3 event(s) processed\nLights: LightsOn\n");
+    }
+
+    #[test]
+    fn entry_blocks_and_main_run_before_dispatch() {
+        let (engine, report) = run("
+            event Add(int);
+
+            actor Counter {
+                int total = 0;
+                on Add(n) { total = total * 10 + n; }
+            }
+
+            actor Starter {
+                entry { Counter ! Add(1); }
+            }
+
+            func main() {
+                Counter ! Add(2);
+                Counter ! Add(3);
+            }
+        ");
+
+        assert_eq!(report.events_processed, 3);
+        assert_eq!(var(&engine, "Counter", "total").to_string(), "123");
+    }
+
+    #[test]
+    fn programs_without_main_cannot_run() {
+        let mut engine = compile("event Ping();").unwrap();
+        let Err(ProteusError::Runtime { error, .. }) = engine.run_main(Limit::Quiescent) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(error.to_string(), "no func named `main`");
+    }
+
+    #[test]
+    fn expressions_and_scopes() {
+        let (engine, _) = run(r#"
+            event Go(int);
+
+            actor Calc {
+                int n = 0;
+                float ratio = 0.0;
+                bool flags = false;
+                string words = "";
+                statemachine {
+                    initial Counting;
+                    state Counting {
+                        int step = 10;
+                        on Go(k) stay {
+                            int twice = k * 2 + step;
+                            n = twice - 1;
+                            ratio = 7 / 2.0;
+                            flags = (1 < 2) && (!(3 == 4));
+                            words = words + "ab";
+                        }
+                    }
+                }
+            }
+
+            func main() {
+                Calc ! Go(20);
+                Calc ! Go(1);
+            }
+        "#);
+
+        assert_eq!(var(&engine, "Calc", "n").to_string(), "11");
+        assert_eq!(var(&engine, "Calc", "ratio").to_string(), "3.5");
+        assert_eq!(var(&engine, "Calc", "flags").to_string(), "true");
+        assert_eq!(var(&engine, "Calc", "words").to_string(), "\"abab\"");
+    }
+
+    #[test]
+    fn logical_operators_bind_looser_than_comparisons() {
+        let (engine, _) = run("
+            actor A {
+                bool both = 1 < 2 && 3 < 4;
+                bool either = 1 > 2 || 2 + 2 == 4 ^^ false;
+                bool negated = !false && 2 > 3;
+            }
+            func main() { }
+        ");
+
+        assert_eq!(var(&engine, "A", "both").to_string(), "true");
+        assert_eq!(var(&engine, "A", "either").to_string(), "true");
+        assert_eq!(var(&engine, "A", "negated").to_string(), "false");
+    }
+
+    #[test]
+    fn funcs_take_arguments_and_return_values() {
+        let (engine, _) = run(r#"
+            event Go(int);
+
+            func square(int n) -> int { return n * n; }
+            func greet(string name) -> string { return "hi " + name; }
+            func half(float x) -> float { return x / 2; }
+
+            actor Calc {
+                int sq = 0;
+                string msg = "";
+                float f = 0.0;
+                on Go(k) {
+                    sq = square(k) + square(square(2));
+                    msg = greet("bob");
+                    f = half(k);
+                }
+            }
+
+            func main() {
+                Calc ! Go(3);
+            }
+        "#);
+
+        assert_eq!(var(&engine, "Calc", "sq").to_string(), "25");
+        assert_eq!(var(&engine, "Calc", "msg").to_string(), "\"hi bob\"");
+        assert_eq!(var(&engine, "Calc", "f").to_string(), "1.5");
+    }
+
+    #[test]
+    fn expressions_and_funcs() {
+        let (engine, _) = run(r#"
+            event Go();
+
+            func fact(int n) -> int {
+                if n <= 1 { return 1; }
+                return n * fact(n - 1);
+            }
+
+            func describe(int n) -> string {
+                match n {
+                    0 => { return "zero"; }
+                    1 => { return "one"; }
+                    _ => { return "many"; }
+                }
+            }
+
+            actor Calc {
+                int product = 0;
+                float ratio = 0.0;
+                bool flags = false;
+                string words = "";
+                on Go() {
+                    product = fact(5);
+                    ratio = 7 / 2.0;
+                    flags = (1 < 2) && (!(3 == 4)) ^^ false;
+                    int i = 0;
+                    while i < 3 {
+                        words = words + describe(i) + ",";
+                        i = i + 1;
+                    }
+                }
+            }
+
+            func main() {
+                Calc ! Go();
+            }
+        "#);
+
+        assert_eq!(var(&engine, "Calc", "product").to_string(), "120");
+        assert_eq!(var(&engine, "Calc", "ratio").to_string(), "3.5");
+        assert_eq!(var(&engine, "Calc", "flags").to_string(), "true");
+        assert_eq!(var(&engine, "Calc", "words").to_string(), "\"zero,one,many,\"");
+    }
+
+    #[test]
+    fn division_by_zero_is_a_runtime_error() {
+        let mut engine = compile("
+            func main() {
+                int zero = 0;
+                int x = 1 / zero;
+            }
+        ").unwrap();
+
+        let Err(ProteusError::Runtime { location, .. }) = engine.run_main(Limit::Quiescent) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(location.map(|location| location.line), Some(4));
+    }
+
+    #[test]
+    fn each_spawn_is_a_new_instance() {
+        let (engine, report) = run("
+            event Tick();
+
+            actor Counter(int step) {
+                int total = 0;
+                on Tick() { total = total + step; }
+            }
+
+            actor Hub {
+                actor kept = spawn Counter(1);
+                entry {
+                    actor other = spawn Counter(10);
+                    kept ! Tick();
+                    other ! Tick();
+                    other ! Tick();
+                }
+            }
+
+            func main() { }
+        ");
+
+        let mut totals: Vec<_> = engine.instances.values()
+            .filter(|actor| actor.name == "Counter")
+            .map(|actor| actor.env["total"].1.to_string())
+            .collect();
+        totals.sort();
+        assert_eq!(totals, ["1", "20"]);
+        assert_eq!(report.final_states.len(), 3);
+    }
+
+    #[test]
+    fn spawned_instances_and_references() {
+        let (engine, report) = run("
+            event Ping();
+            event Pong(int);
+
+            actor Echo(int id) {
+                on Ping() { sender ! Pong(id); }
+            }
+
+            actor Hub {
+                int sum = 0;
+                int replies = 0;
+                entry {
+                    actor a = spawn Echo(1);
+                    actor b = spawn Echo(20);
+                    a ! Ping();
+                    b ! Ping();
+                }
+                on Pong(n) {
+                    sum = sum + n;
+                    replies = replies + 1;
+                    if replies == 2 { self ! Pong(100); }
+                }
+            }
+
+            func main() { }
+        ");
+
+        assert_eq!(var(&engine, "Hub", "sum").to_string(), "121");
+        assert_eq!(report.final_states.len(), 3);
+    }
+
+    #[test]
+    fn events_bubble_up_and_exit_through_the_common_ancestor() {
+        let (engine, _) = run(r#"
+            event Move();
+            event Reset();
+            event Ping();
+
+            actor Machine {
+                string log = "";
+                int pings = 0;
+                on Ping() { pings = pings + 1; }
+                statemachine {
+                    initial Outer;
+                    state Outer {
+                        initial A;
+                        entry { log = log + "+Outer"; }
+                        exit { log = log + "-Outer"; }
+                        on Reset() goto Other;
+                        state A {
+                            entry { log = log + "+A"; }
+                            exit { log = log + "-A"; }
+                            on Move() goto B;
+                        }
+                        state B {
+                            entry { log = log + "+B"; }
+                            exit { log = log + "-B"; }
+                        }
+                    }
+                    state Other {
+                        entry { log = log + "+Other"; }
+                    }
+                }
+            }
+
+            func main() {
+                Machine ! Ping();
+                Machine ! Move();
+                Machine ! Reset();
+            }
+        "#);
+
+        assert_eq!(var(&engine, "Machine", "log").to_string(), "\"+Outer+A-A+B-B-Outer+Other\"");
+        assert_eq!(var(&engine, "Machine", "pings").to_string(), "1");
+        assert_eq!(states(&engine, "Machine"), vec!["Other"]);
+    }
+
+    #[test]
+    fn actor_handlers_see_the_state_machine_root() {
+        let (engine, _) = run(r#"
+            event Ping();
+
+            actor Counter {
+                int seen = 0;
+                entry { count = 10; }
+                on Ping() { count = count + 1; seen = count; }
+                statemachine {
+                    int count = 0;
+                    initial Idle;
+                    state Idle { }
+                }
+            }
+
+            func main() {
+                Counter ! Ping();
+                Counter ! Ping();
+            }
+        "#);
+
+        assert_eq!(var(&engine, "Counter", "seen").to_string(), "12");
+    }
+
+    #[test]
+    fn parallel_regions_all_take_an_event() {
+        let (engine, _) = run("
+            event Toggle();
+
+            actor Panel {
+                statemachine {
+                    initial Both;
+                    parallel Both {
+                        region Left {
+                            initial Off;
+                            state Off { on Toggle() goto On; }
+                            state On { }
+                        }
+                        region Right {
+                            initial Idle;
+                            state Idle { on Toggle() goto Busy; }
+                            state Busy { }
+                        }
+                    }
+                }
+            }
+
+            func main() {
+                Panel ! Toggle();
+            }
+        ");
+
+        assert_eq!(states(&engine, "Panel"), vec!["Both.Left.On", "Both.Right.Busy"]);
+    }
+
+    #[test]
+    fn shallow_and_deep_history() {
+        let program = |history: &str| format!("
+            event Next();
+            event Leave();
+            event Back();
+
+            actor Player {{
+                statemachine {{
+                    initial Playing;
+                    state Playing {{
+                        {}
+                        initial Track;
+                        on Leave() goto Paused;
+                        state Track {{
+                            initial Intro;
+                            state Intro {{ on Next() goto Chorus; }}
+                            state Chorus {{ }}
+                        }}
+                    }}
+                    state Paused {{
+                        on Back() goto Playing;
+                    }}
+                }}
+            }}
+
+            func main() {{
+                Player ! Next();
+                Player ! Leave();
+                Player ! Back();
+            }}
+        ", history);
+
+        let (engine, _) = run(&program("history;"));
+        assert_eq!(states(&engine, "Player"), vec!["Playing.Track.Intro"]);
+
+        let (engine, _) = run(&program("deep history;"));
+        assert_eq!(states(&engine, "Player"), vec!["Playing.Track.Chorus"]);
+    }
+
+    #[test]
+    fn timeouts_and_delayed_sends() {
+        let (engine, _) = run("
+            event Tick(int);
+            event Go();
+
+            actor Clock {
+                int order = 0;
+                on Tick(n) { order = order * 10 + n; }
+            }
+
+            actor Door {
+                statemachine {
+                    initial Open;
+                    state Open { on after(500ms) goto Closed; }
+                    state Closed { }
+                }
+            }
+
+            actor Guard {
+                statemachine {
+                    initial Waiting;
+                    state Waiting {
+                        on after(1s) goto TimedOut;
+                        on Go() goto Done;
+                    }
+                    state TimedOut { }
+                    state Done { }
+                }
+            }
+
+            func main() {
+                Clock ! Tick(3) after 3s;
+                Clock ! Tick(1) after 1s;
+                Clock ! Tick(2) after 2s;
+                Guard ! Go() after 600ms;
+            }
+        ");
+
+        assert_eq!(var(&engine, "Clock", "order").to_string(), "123");
+        assert_eq!(states(&engine, "Door"), vec!["Closed"]);
+        assert_eq!(states(&engine, "Guard"), vec!["Done"]);
+        assert_eq!(engine.clock.now(), 3_000);
+    }
+
+    #[test]
+    fn timers_fire_in_order_on_a_virtual_clock() {
+        let (engine, _) = run("
+            event Tick(int);
+
+            actor Clock {
+                list<int> ticks = list<int>[];
+                on Tick(n) { insert(ticks, n); }
+            }
+
+            func main() {
+                Clock ! Tick(3) after 3s;
+                Clock ! Tick(1) after 1s;
+                Clock ! Tick(2) after 2s;
+            }
+        ");
+
+        assert_eq!(var(&engine, "Clock", "ticks").to_string(), "[1, 2, 3]");
+        assert_eq!(engine.clock.now(), 3_000);
+    }
+
+    #[test]
+    fn exited_states_do_not_move_the_clock() {
+        let (engine, _) = run("
+            event Go();
+
+            actor Lamp {
+                statemachine {
+                    initial Waiting;
+                    state Waiting {
+                        on after(5s) goto Late;
+                        on Go() goto Ready;
+                    }
+                    state Ready { }
+                    state Late { }
+                }
+            }
+
+            func main() {
+                Lamp ! Go();
+            }
+        ");
+
+        assert_eq!(states(&engine, "Lamp"), vec!["Ready"]);
+        assert_eq!(engine.clock.now(), 0);
+    }
+
+    #[test]
+    fn durations_that_overflow() {
+        let Err(ProteusError::Parse { message, location }) = compile("
+            event Ping();
+            func main() {
+                main ! Ping() after 999999999999999999m;
+            }
+        ") else {
+            panic!("expected a parse error");
+        };
+
+        assert_eq!(message, "duration is too long");
+        assert_eq!((location.line, location.column), (4, 37));
+    }
+
+    #[test]
+    fn deferred_events_and_priorities() {
+        let (engine, _) = run(r#"
+            event Open();
+            event Work(int);
+            event Urgent(int) priority 1;
+
+            actor Desk {
+                string log = "";
+                statemachine {
+                    initial Closed;
+                    state Closed {
+                        defer Work;
+                        defer Urgent;
+                        on Open() goto Ready;
+                    }
+                    state Ready {
+                        on Work(n) stay { log = log + "w"; }
+                        on Urgent(n) stay { log = log + "u"; }
+                    }
+                }
+            }
+
+            func main() {
+                Desk ! Work(1);
+                Desk ! Urgent(2);
+                Desk ! Work(3);
+                Desk ! Open();
+            }
+        "#);
+
+        assert_eq!(var(&engine, "Desk", "log").to_string(), "\"uww\"");
+    }
+
+    #[test]
+    fn choice_states_pick_the_first_branch_that_holds() {
+        let (engine, _) = run("
+            event Grade(int);
+
+            actor Judge {
+                int score = 0;
+                statemachine {
+                    initial Waiting;
+                    state Waiting {
+                        on Grade(n) goto Decide { score = n; }
+                    }
+                    choice Decide {
+                        if score >= 90 goto High;
+                        if score >= 50 goto Middle;
+                        else goto Low;
+                    }
+                    state High { }
+                    state Middle { }
+                    state Low { }
+                }
+            }
+
+            func main() {
+                Judge ! Grade(75);
+            }
+        ");
+
+        assert_eq!(states(&engine, "Judge"), vec!["Middle"]);
+    }
+
+    #[test]
+    fn guards_that_are_not_bool_are_runtime_errors() {
+        // Loaded without checking, so the guard reaches the runtime as is.
+        let mut engine = EvalEngine::default();
+        engine.load_from_string("
+            event Go(int);
+            actor A { on Go(n) if n { } }
+            func main() { A ! Go(1); }
+        ").unwrap();
+
+        let Err(ProteusError::Runtime { error, .. }) = engine.run_main(Limit::Quiescent) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(error.to_string(), "condition must be `bool`, found `int`");
+    }
+
+    #[test]
+    fn reaching_a_final_state_completes_the_parent() {
+        let (engine, report) = run("
+            event Step();
+            event Stop();
+
+            actor Job {
+                statemachine {
+                    initial Running;
+                    state Running {
+                        initial First;
+                        on done goto Finished;
+                        state First { on Step() goto Last; }
+                        final Last;
+                    }
+                    state Finished { }
+                }
+            }
+
+            actor Worker {
+                on Stop() { terminate; }
+            }
+
+            func main() {
+                Job ! Step();
+                Worker ! Stop();
+            }
+        ");
+
+        assert_eq!(states(&engine, "Job"), vec!["Finished"]);
+        assert!(!report.final_states.contains_key("Worker"));
+    }
+
+    #[test]
+    fn internal_external_and_local_transitions() {
+        let (engine, _) = run(r#"
+            event Inner();
+            event Outer();
+            event Local();
+
+            actor Door {
+                string log = "";
+                statemachine {
+                    initial Room;
+                    state Room {
+                        initial Hall;
+                        entry { log = log + "+Room"; }
+                        exit { log = log + "-Room"; }
+                        on Inner() stay { log = log + "!"; }
+                        on Outer() goto Room;
+                        on Local() goto local Hall;
+                        state Hall {
+                            entry { log = log + "+Hall"; }
+                            exit { log = log + "-Hall"; }
+                        }
+                    }
+                }
+            }
+
+            func main() {
+                Door ! Inner();
+                Door ! Outer();
+                Door ! Local();
+            }
+        "#);
+
+        assert_eq!(var(&engine, "Door", "log").to_string(),
+                   "\"+Room+Hall!-Hall-Room+Room+Hall-Hall+Hall\"");
+    }
+
+    #[test]
+    fn records_lists_and_maps() {
+        let (engine, _) = run(r#"
+            record Order { int id; string item; float qty; }
+            event Place(Order);
+
+            actor Shop {
+                list<Order> queue = list<Order>[];
+                map<string, int> stock = ["apple": 3, "pear": 1];
+                on Place(o) {
+                    insert(queue, o);
+                    stock[o.item] = stock[o.item] - 1;
+                    queue[len(queue) - 1].qty = queue[len(queue) - 1].qty * 2;
+                }
+            }
+
+            func main() {
+                Shop ! Place(Order(1, "apple", 2));
+                Shop ! Place(Order(2, "pear", 1));
+            }
+        "#);
+
+        assert_eq!(var(&engine, "Shop", "stock").to_string(), r#"["apple": 2, "pear": 0]"#);
+        assert_eq!(var(&engine, "Shop", "queue").to_string(),
+                   r#"[Order(id: 1, item: "apple", qty: 4), Order(id: 2, item: "pear", qty: 2)]"#);
+    }
+
+    #[test]
+    fn enums_in_events_guards_and_match() {
+        let (engine, _) = run(r#"
+            enum Mode { Low, Medium, High }
+            event Set(Mode);
+
+            func label(Mode m) -> string {
+                match m {
+                    Mode::Low => { return "low"; }
+                    Mode::Medium => { return "mid"; }
+                    Mode::High => { return "high"; }
+                }
+            }
+
+            actor Fan {
+                string log = "";
+                statemachine {
+                    initial Quiet;
+                    state Quiet {
+                        on Set(m) goto Loud if m == Mode::High { }
+                        on Set(m) stay else { log = log + label(m) + ","; }
+                    }
+                    state Loud { }
+                }
+            }
+
+            func main() {
+                Fan ! Set(Mode::Medium);
+                Fan ! Set(Mode::High);
+                Fan ! Set(Mode::Low);
+            }
+        "#);
+
+        assert_eq!(var(&engine, "Fan", "log").to_string(), "\"mid,\"");
+        assert_eq!(states(&engine, "Fan"), vec!["Loud"]);
+    }
+}
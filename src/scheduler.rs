@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+use crate::error::ProteusError;
+use crate::eval::EvalEngine;
+
+/* SCHEDULING */
+
+/// How the scheduler picks the next event to handle when several are
+/// waiting. Either way every actor handles its own events in the order
+/// they were delivered.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Policy {
+    /// Events are handled in the order they were delivered.
+    #[default]
+    Fifo,
+    /// The next event is drawn at random from those waiting, using a
+    /// generator started from `seed`, so a run can be repeated exactly.
+    Random { seed: u64 },
+}
+
+/// How far `run_main` goes once `func main()` has returned.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Limit {
+    /// Until no event is waiting and no timer is pending.
+    #[default]
+    Quiescent,
+    /// At most this many steps.
+    Steps(usize),
+    /// Everything that happens up to this clock time, in milliseconds.
+    Time(u64),
+}
+
+/// What a single step of the scheduler did.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Step {
+    Event,
+    Timer,
+    Idle,
+}
+
+/// Decides which actor handles an event next. `ready` holds the id of the
+/// receiving instance for each event waiting in an inbox, in the order the
+/// events were delivered.
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Scheduler {
+    pub policy: Policy,
+    ready: VecDeque<u32>,
+    rng: u64,
+}
+
+impl Scheduler {
+    pub fn new(policy: Policy) -> Self {
+        let rng = match policy { Policy::Random { seed } => seed, Policy::Fifo => 0 };
+        Scheduler { policy, ready: VecDeque::new(), rng }
+    }
+
+    /// Records that an event was delivered to `actor`.
+    pub(crate) fn ready(&mut self, actor: u32) {
+        self.ready.push_back(actor);
+    }
+
+    /// Hands over every waiting entry, oldest first, leaving none behind.
+    pub(crate) fn take_ready(&mut self) -> VecDeque<u32> {
+        std::mem::take(&mut self.ready)
+    }
+
+    fn next(&mut self) -> Option<u32> {
+        match self.policy {
+            Policy::Fifo => self.ready.pop_front(),
+            Policy::Random { .. } if self.ready.is_empty() => None,
+            Policy::Random { .. } => {
+                let index = self.random() % self.ready.len() as u64;
+                self.ready.remove(index as usize)
+            }
+        }
+    }
+
+    /// SplitMix64: small, fast and the same on every platform.
+    fn random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl EvalEngine {
+    /// Changes how the next event is picked from now on. Events already
+    /// waiting stay where they are.
+    pub fn set_policy(&mut self, policy: Policy) {
+        let ready = std::mem::take(&mut self.scheduler.ready);
+        self.scheduler = Scheduler { ready, ..Scheduler::new(policy) };
+    }
+
+    /// Takes one step: fires a timer that is already due, otherwise handles
+    /// the next event, otherwise moves the clock on to the next timer unless
+    /// it is due after `limit`.
+    fn step_until(&mut self, limit: Option<u64>) -> Result<Step, ProteusError> {
+        let due = self.next_timer();
+        if due.is_some_and(|due| due <= self.clock.now()) {
+            self.fire_timer()?;
+            return Ok(Step::Timer);
+        }
+
+        while let Some(actor) = self.scheduler.next() {
+            if self.handle_next(actor)? {
+                return Ok(Step::Event);
+            }
+        }
+
+        match due {
+            Some(due) if limit.is_none_or(|limit| due <= limit) => {
+                self.fire_timer()?;
+                Ok(Step::Timer)
+            }
+            _ => Ok(Step::Idle),
+        }
+    }
+
+    /// Steps until `done` says to stop or there is nothing left to do within
+    /// `limit`. Returns how many events were handled.
+    fn run(&mut self, limit: Option<u64>, mut done: impl FnMut(usize) -> bool) -> Result<usize, ProteusError> {
+        let (mut steps, mut events) = (0, 0);
+        while !done(steps) {
+            match self.step_until(limit)? {
+                Step::Event => events += 1,
+                Step::Timer => {}
+                Step::Idle => break,
+            }
+            steps += 1;
+        }
+
+        Ok(events)
+    }
+
+    /// Runs as far as `limit` allows. Returns how many events were handled.
+    pub fn run_until(&mut self, limit: Limit) -> Result<usize, ProteusError> {
+        match limit {
+            Limit::Quiescent => self.until_quiescent(),
+            Limit::Steps(steps) => self.for_steps(steps),
+            Limit::Time(time) => self.until_time(time),
+        }
+    }
+
+    /// Runs until no event is waiting and no timer is pending. Returns how
+    /// many events were handled.
+    pub fn until_quiescent(&mut self) -> Result<usize, ProteusError> {
+        self.run(None, |_| false)
+    }
+
+    /// Takes at most `steps` steps. Returns how many events were handled.
+    pub fn for_steps(&mut self, steps: usize) -> Result<usize, ProteusError> {
+        self.run(None, |taken| taken >= steps)
+    }
+
+    /// Runs everything that happens up to clock time `time`, then moves the
+    /// clock there. Returns how many events were handled.
+    pub fn until_time(&mut self, time: u64) -> Result<usize, ProteusError> {
+        let events = self.run(Some(time), |_| false)?;
+        self.clock.advance_to(time);
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{compile, var};
+    use super::{Limit, Policy};
+
+    const PROGRAM: &str = "
+        event Hit(int);
+        event Note(int);
+
+        actor Log {
+            int seen = 0;
+            on Note(n) { seen = seen * 10 + n; }
+        }
+        actor A { on Hit(n) { Log ! Note(n); } }
+        actor B { on Hit(n) { Log ! Note(n * 2); } }
+
+        func main() {
+            A ! Hit(1);
+            B ! Hit(2);
+            A ! Hit(3);
+            B ! Hit(4);
+            Log ! Note(9);
+        }
+    ";
+
+    /// The notes the log saw, one digit each in the order it saw them, when
+    /// run under `policy`.
+    fn trace(policy: Policy, limit: Limit) -> String {
+        let mut engine = compile(PROGRAM).unwrap();
+        engine.set_policy(policy);
+        engine.run_main(limit).unwrap();
+        var(&engine, "Log", "seen").to_string()
+    }
+
+    #[test]
+    fn fifo_handles_events_in_delivery_order() {
+        assert_eq!(trace(Policy::Fifo, Limit::Quiescent), "91438");
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_trace() {
+        let sorted = |trace: &str| {
+            let mut notes: Vec<_> = trace.chars().collect();
+            notes.sort();
+            notes
+        };
+        let fifo = trace(Policy::Fifo, Limit::Quiescent);
+
+        for seed in [1, 7, 42] {
+            let first = trace(Policy::Random { seed }, Limit::Quiescent);
+            assert_eq!(first, trace(Policy::Random { seed }, Limit::Quiescent));
+            assert_eq!(sorted(&first), sorted(&fifo));
+        }
+
+        assert_ne!(trace(Policy::Random { seed: 42 }, Limit::Quiescent), fifo);
+    }
+
+    #[test]
+    fn steps_limit_the_run() {
+        assert_eq!(trace(Policy::Fifo, Limit::Steps(6)), "91");
+    }
+
+    #[test]
+    fn time_limits_the_run() {
+        let mut engine = compile("
+            event Tick();
+            actor Clock {
+                int ticks = 0;
+                on Tick() { ticks = ticks + 1; self ! Tick() after 1s; }
+            }
+            func main() {
+                Clock ! Tick();
+            }
+        ").unwrap();
+
+        engine.run_main(Limit::Time(3_500)).unwrap();
+        assert_eq!(var(&engine, "Clock", "ticks").to_string(), "4");
+        assert_eq!(engine.clock.now(), 3_500);
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+use crate::ast::Span;
+
+/// The text of one loaded program, kept around so spans can be turned back
+/// into file names, lines and columns when something has to be reported.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+pub struct Source {
+    pub id: usize,
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    pub fn new(id: usize, name: &str, text: String) -> Self {
+        Source { id, name: name.to_string(), text }
+    }
+
+    /// The nearest character boundary at or before `offset`.
+    fn floor(&self, offset: usize) -> usize {
+        let mut offset = offset.min(self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        offset
+    }
+
+    /// Finds the line a span starts on. Spans reaching past the end of that
+    /// line are cut off there, so the caret never wraps. Offsets inside a
+    /// character are moved back to its start.
+    pub fn locate(&self, span: Span) -> Location {
+        let start = self.floor(span.start);
+        let line_start = self.text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.text[start..].find('\n').map_or(self.text.len(), |i| start + i);
+        let line_text = self.text[line_start..line_end].trim_end_matches('\r');
+
+        let end = self.floor(span.end.clamp(start, line_start + line_text.len()));
+        Location {
+            file: self.name.clone(),
+            line: self.text[..start].matches('\n').count() + 1,
+            column: self.text[line_start..start].chars().count() + 1,
+            line_text: line_text.to_string(),
+            width: self.text[start..end].chars().count().max(1),
+        }
+    }
+}
+
+/// A position in a source file as people read it: 1-based line and column,
+/// along with the text of that line and how many characters to underline.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub line_text: String,
+    pub width: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        let indent: String = self.line_text.chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "{}--> {}:{}:{}", gutter, self.file, self.line, self.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.line_text)?;
+        write!(f, "{} | {}{}", gutter, indent, "^".repeat(self.width))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::Span;
+    use crate::error::ProteusError;
+    use crate::eval::EvalEngine;
+    use super::Source;
+
+    #[test]
+    fn locations_are_lines_and_columns() {
+        let source = Source::new(1, "test.pro", "event Ping();\n  func f() { }\n".to_string());
+        let location = source.locate(Span { file: 1, start: 16, end: 20 });
+
+        assert_eq!((location.line, location.column, location.width), (2, 3, 4));
+        assert_eq!(location.to_string(), " --> test.pro:2:3\n  |\n2 |   func f() { }\n  |   ^^^^");
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_unexpected_token() {
+        let mut engine = EvalEngine::default();
+        let Err(ProteusError::Parse { message, location }) = engine.load_from_string("event Ping();\nfunc f() { int x = ; }") else {
+            panic!("expected a parse error");
+        };
+
+        assert!(message.starts_with("unexpected `;`"));
+        assert_eq!((location.file.as_str(), location.line, location.column), ("<string>", 2, 20));
+    }
+
+    #[test]
+    fn expected_tokens_are_named() {
+        let message = |text: &str| match EvalEngine::default().load_from_string(text) {
+            Err(ProteusError::Parse { message, .. }) => message,
+            _ => panic!("expected a parse error"),
+        };
+
+        assert_eq!(message("import 1;"), "unexpected `1`, expected string");
+        assert_eq!(message("actor 1 { }"), "unexpected `1`, expected identifier");
+        assert_eq!(message("func f() { A ! Ping() after ; }"), "unexpected `;`, expected duration");
+
+        let expr = message("func f() { int x = ; }");
+        for name in ["identifier", "string", "int", "float"] {
+            assert!(expr.contains(name), "{}", expr);
+        }
+        assert!(!expr.contains("r#"), "{}", expr);
+    }
+
+    #[test]
+    fn spans_inside_a_character() {
+        let source = Source::new(1, "test.pro", "int é = 1;\nx".to_string());
+        let location = source.locate(Span { file: 1, start: 5, end: 6 });
+
+        assert_eq!((location.line, location.column, location.width), (1, 5, 1));
+    }
+
+    #[test]
+    fn invalid_token_is_a_whole_character() {
+        let mut engine = EvalEngine::default();
+        let Err(ProteusError::Parse { location, .. }) = engine.load_from_string("event Ping();\n€") else {
+            panic!("expected a parse error");
+        };
+
+        assert_eq!((location.line, location.column, location.width), (2, 1, 1));
+    }
+
+    #[test]
+    fn int_literals_that_overflow() {
+        let mut engine = EvalEngine::default();
+        let Err(ProteusError::Parse { message, location }) = engine.load_from_string("func f() -> int { return 9223372036854775808; }") else {
+            panic!("expected a parse error");
+        };
+
+        assert_eq!(message, "integer literal is too large");
+        assert_eq!((location.column, location.width), (26, 19));
+    }
+}
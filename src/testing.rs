@@ -0,0 +1,49 @@
+use crate::error::ProteusError;
+use crate::eval::{Actor, EvalEngine, Value};
+use crate::runtime::{active_paths, RunReport};
+use crate::scheduler::Limit;
+use crate::typecheck::DiagnosticKind;
+
+/* TEST HELPERS */
+
+/// Loads and checks a program held in memory.
+pub fn compile(text: &str) -> Result<EvalEngine, ProteusError> {
+    let mut engine = EvalEngine::default();
+    engine.load_from_string(text)?;
+    engine.compile()?;
+    Ok(engine)
+}
+
+/// The problems checking a program reports, in the order they are reported.
+pub fn diagnostics(text: &str) -> Vec<DiagnosticKind> {
+    match compile(text) {
+        Err(ProteusError::Semantic(diagnostics) | ProteusError::Type(diagnostics)) =>
+            diagnostics.into_iter().map(|diagnostic| diagnostic.kind).collect(),
+        Err(err) => panic!("expected diagnostics, got: {}", err),
+        Ok(_) => panic!("expected diagnostics, but the program compiled"),
+    }
+}
+
+/// Checks a program and runs its `func main()` to the end.
+pub fn run(text: &str) -> (EvalEngine, RunReport) {
+    let mut engine = compile(text).unwrap_or_else(|err| panic!("{}", err));
+    let report = engine.run_main(Limit::Quiescent).unwrap_or_else(|err| panic!("{}", err));
+    (engine, report)
+}
+
+/// The instance started for an actor without parameters.
+fn instance<'e>(engine: &'e EvalEngine, actor: &str) -> &'e Actor {
+    &engine.instances[&engine.globals.named[actor].id]
+}
+
+/// The value of a variable of an actor's default instance.
+pub fn var(engine: &EvalEngine, actor: &str, name: &str) -> Value {
+    instance(engine, actor).env[name].1.clone()
+}
+
+/// The innermost active states of an actor's default instance, written
+/// `Outer.Inner`.
+pub fn states(engine: &EvalEngine, actor: &str) -> Vec<String> {
+    let paths = instance(engine, actor).statemachine.as_ref().map(active_paths).unwrap_or_default();
+    paths.into_iter().map(|path| path.join(".")).collect()
+}
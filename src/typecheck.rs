@@ -0,0 +1,1313 @@
+use std::collections::HashMap;
+use std::fmt;
+use multimap::MultiMap;
+use crate::ast::*;
+use crate::eval::*;
+use crate::resolve::{in_order, Namespace};
+use crate::runtime::BUILTINS;
+use crate::source::{Location, Source};
+
+/* DIAGNOSTICS */
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum DiagnosticKind {
+    TypeMismatch { expected: VarType, found: VarType },
+    InvalidOperands { op: &'static str, left: VarType, right: VarType },
+    InvalidOperand { op: &'static str, operand: VarType },
+    NonBoolCondition { found: VarType },
+    InvalidMatch(VarType),
+    UnreachablePattern(String),
+    NonExhaustiveMatch(Vec<String>),
+    UnknownVariable(String),
+    UnknownFunction(String),
+    UnknownEvent(String),
+    EventParameter,
+    UnknownActor(String),
+    UnknownType(String),
+    UnknownVariant { enum_name: String, variant: String },
+    DuplicateVariant(String),
+    InvalidKey(VarType),
+    NotIndexable(VarType),
+    UnknownField { typ: VarType, field: String },
+    NotAssignable,
+    BuiltinArgument { func: String, found: VarType },
+    BuiltinName(String),
+    NoDefaultInstance(String),
+    SelfOutsideActor,
+    TerminateOutsideActor,
+    SenderOutsideHandler,
+    ArgumentCount { func: String, expected: usize, found: usize },
+    EventArgumentCount { event: String, expected: usize, found: usize },
+    VoidValue(String),
+    MissingReturn(String),
+    MissingReturnValue(VarType),
+    UnexpectedReturnValue,
+    UnknownTarget(String),
+    MissingInitial,
+    UnknownInitial(String),
+    ChoiceInitial(String),
+    DuplicateState(String),
+    DuplicateBlock(String),
+    ParallelInitial,
+    UselessHistory,
+    MisplacedRegion { parallel: bool },
+    UnreachableState,
+    ShadowedHandler(String),
+    ShadowedBranch,
+    NeverDone,
+    UnknownModule(String),
+    ImportCycle(String),
+    UnknownImport { module: String, name: String },
+    DuplicateDeclaration { name: String, module: String, first: String },
+    Redeclared(String),
+    ModuleClash { module: String, file: String },
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticKind::TypeMismatch { expected, found } =>
+                write!(f, "expected `{}`, found `{}`", expected, found),
+            DiagnosticKind::InvalidOperands { op, left, right } =>
+                write!(f, "operator `{}` cannot be applied to `{}` and `{}`", op, left, right),
+            DiagnosticKind::InvalidOperand { op, operand } =>
+                write!(f, "operator `{}` cannot be applied to `{}`", op, operand),
+            DiagnosticKind::NonBoolCondition { found } =>
+                write!(f, "condition must be `bool`, found `{}`", found),
+            DiagnosticKind::InvalidMatch(typ) =>
+                write!(f, "cannot match on `{}`, only `int`, `string` and enum values", typ),
+            DiagnosticKind::UnreachablePattern(pattern) =>
+                write!(f, "pattern `{}` can never be reached", pattern),
+            DiagnosticKind::NonExhaustiveMatch(missing) =>
+                write!(f, "match does not cover `{}`, add arms for them or `_`", missing.join("`, `")),
+            DiagnosticKind::UnknownVariable(name) =>
+                write!(f, "unknown variable `{}`", name),
+            DiagnosticKind::UnknownFunction(name) =>
+                write!(f, "unknown function `{}`", name),
+            DiagnosticKind::UnknownEvent(name) =>
+                write!(f, "unknown event `{}`", name),
+            DiagnosticKind::EventParameter =>
+                write!(f, "event parameters must be identifiers"),
+            DiagnosticKind::UnknownActor(name) =>
+                write!(f, "unknown actor `{}`", name),
+            DiagnosticKind::UnknownType(name) =>
+                write!(f, "unknown type `{}`", name),
+            DiagnosticKind::UnknownVariant { enum_name, variant } =>
+                write!(f, "enum `{}` has no variant `{}`", enum_name, variant),
+            DiagnosticKind::DuplicateVariant(variant) =>
+                write!(f, "variant `{}` is declared more than once", variant),
+            DiagnosticKind::InvalidKey(typ) =>
+                write!(f, "`{}` cannot be used as a map key, only `int`, `bool`, `string`, `actor` and enum values", typ),
+            DiagnosticKind::NotIndexable(typ) =>
+                write!(f, "`{}` cannot be indexed, only lists and maps", typ),
+            DiagnosticKind::UnknownField { typ, field } =>
+                write!(f, "`{}` has no field `{}`", typ, field),
+            DiagnosticKind::NotAssignable =>
+                write!(f, "only variables and their elements and fields can be assigned to"),
+            DiagnosticKind::BuiltinArgument { func, found } =>
+                write!(f, "`{}` cannot be applied to `{}`", func, found),
+            DiagnosticKind::BuiltinName(name) =>
+                write!(f, "`{}` is a built-in operation and cannot be declared again", name),
+            DiagnosticKind::NoDefaultInstance(name) =>
+                write!(f, "actor `{}` takes parameters, so it can only be reached through `spawn`", name),
+            DiagnosticKind::SelfOutsideActor =>
+                write!(f, "`self` can only be used inside an actor"),
+            DiagnosticKind::TerminateOutsideActor =>
+                write!(f, "`terminate` can only be used inside an actor"),
+            DiagnosticKind::SenderOutsideHandler =>
+                write!(f, "`sender` can only be used in an event handler"),
+            DiagnosticKind::ArgumentCount { func, expected, found } =>
+                write!(f, "`{}` takes {} argument(s), {} given", func, expected, found),
+            DiagnosticKind::EventArgumentCount { event, expected, found } =>
+                write!(f, "event `{}` carries {} parameter(s), {} given", event, expected, found),
+            DiagnosticKind::VoidValue(name) =>
+                write!(f, "`{}` has no return type and cannot be used as a value", name),
+            DiagnosticKind::MissingReturn(name) =>
+                write!(f, "`{}` declares a return type but not every path returns a value", name),
+            DiagnosticKind::MissingReturnValue(typ) =>
+                write!(f, "`return` needs a value of type `{}`", typ),
+            DiagnosticKind::UnexpectedReturnValue =>
+                write!(f, "no value can be returned here"),
+            DiagnosticKind::UnknownTarget(name) =>
+                write!(f, "unknown goto target `{}`", name),
+            DiagnosticKind::MissingInitial =>
+                write!(f, "composite state has no `initial` declaration"),
+            DiagnosticKind::UnknownInitial(name) =>
+                write!(f, "initial state `{}` is not a sub-state", name),
+            DiagnosticKind::ChoiceInitial(name) =>
+                write!(f, "initial state `{}` is a choice, which only a transition can pass through", name),
+            DiagnosticKind::DuplicateState(name) =>
+                write!(f, "state `{}` is declared more than once", name),
+            DiagnosticKind::DuplicateBlock(block) =>
+                write!(f, "`{}` is declared more than once here, only the first would run", block),
+            DiagnosticKind::ParallelInitial =>
+                write!(f, "parallel state cannot declare `initial`, all of its regions are entered"),
+            DiagnosticKind::UselessHistory =>
+                write!(f, "`history` has no effect on a state without sub-states"),
+            DiagnosticKind::MisplacedRegion { parallel: true } =>
+                write!(f, "a parallel state can only contain `region`s"),
+            DiagnosticKind::MisplacedRegion { parallel: false } =>
+                write!(f, "`region` can only be declared directly inside a `parallel` state"),
+            DiagnosticKind::UnreachableState =>
+                write!(f, "state can never become active"),
+            DiagnosticKind::ShadowedHandler(event) =>
+                write!(f, "handler for `{}` is never taken, another one without a guard always applies first", event),
+            DiagnosticKind::ShadowedBranch =>
+                write!(f, "branch is never taken, another one without a guard always applies first"),
+            DiagnosticKind::NeverDone =>
+                write!(f, "`on done` is never taken, the state has no `final` sub-state to reach"),
+            DiagnosticKind::UnknownModule(file) =>
+                write!(f, "cannot find `{}` next to this file or in any search path", file),
+            DiagnosticKind::ImportCycle(cycle) =>
+                write!(f, "import cycle: {}", cycle),
+            DiagnosticKind::UnknownImport { module, name } =>
+                write!(f, "module `{}` declares nothing called `{}`", module, name),
+            DiagnosticKind::DuplicateDeclaration { name, module, first } =>
+                write!(f, "`{}` is already declared in module `{}` at {}", name, module, first),
+            DiagnosticKind::Redeclared(name) =>
+                write!(f, "`{}` is already declared in this file", name),
+            DiagnosticKind::ModuleClash { module, file } =>
+                write!(f, "module `{}` is already loaded from `{}`", module, file),
+        }
+    }
+}
+
+/// A single problem found by the checker, along with where it was found
+/// (an actor, a state path such as `Lights.LightsOn`, or a func) and the
+/// source it points at.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Diagnostic {
+    pub context: String,
+    pub kind: DiagnosticKind,
+    pub location: Option<Location>,
+}
+
+impl Diagnostic {
+    pub fn new(context: &str, kind: DiagnosticKind, source: &Source, span: Span) -> Self {
+        Diagnostic { context: context.to_string(), kind, location: Some(source.locate(span)) }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error in {}: {}", self.context, self.kind)?;
+        if let Some(location) = &self.location {
+            write!(f, "\n{}", location)?;
+        }
+        Ok(())
+    }
+}
+
+/* TYPE RULES */
+
+fn is_numeric(typ: &VarType) -> bool {
+    matches!(typ, VarType::IntType | VarType::FloatType)
+}
+
+/// Whether a value of type `found` can be stored where `expected` is required;
+/// ints are promoted to floats, also inside lists and as map values, and
+/// everything else must match exactly.
+pub fn is_assignable(expected: &VarType, found: &VarType) -> bool {
+    match (expected, found) {
+        (VarType::FloatType, VarType::IntType) => true,
+        (VarType::ListType(expected), VarType::ListType(found)) => is_assignable(expected, found),
+        (VarType::MapType(expected_key, expected), VarType::MapType(found_key, found)) =>
+            expected_key == found_key && is_assignable(expected, found),
+        _ => expected == found,
+    }
+}
+
+
+fn promote(l: &VarType, r: &VarType) -> VarType {
+    if *l == VarType::FloatType || *r == VarType::FloatType {
+        VarType::FloatType
+    } else {
+        VarType::IntType
+    }
+}
+
+/// Whether every way through a block ends in a `return`. A `while` loop
+/// never counts, since its body may not run at all. A `match` on enum
+/// variants counts once every arm returns, since one that leaves a variant
+/// out is reported anyway.
+fn always_returns(block: &[Spanned<ControlFlowExpr>]) -> bool {
+    block.iter().any(|stmt| match &stmt.node {
+        ControlFlowExpr::ReturnStatement(_) => true,
+        ControlFlowExpr::IfStatement { branches, otherwise: Some(otherwise) } =>
+            branches.iter().all(|(_, body)| always_returns(body)) && always_returns(otherwise),
+        ControlFlowExpr::MatchStatement { arms, .. } =>
+            arms.iter().any(|(pattern, _)| matches!(pattern, MatchPattern::Wildcard | MatchPattern::Variant { .. }))
+                && arms.iter().all(|(_, body)| always_returns(body)),
+        _ => false,
+    })
+}
+
+fn pattern_type(pattern: &MatchPattern) -> Option<VarType> {
+    match pattern {
+        MatchPattern::Int(_) => Some(VarType::IntType),
+        MatchPattern::Str(_) => Some(VarType::StringType),
+        MatchPattern::Variant { enum_name, .. } => Some(VarType::NamedType(enum_name.clone())),
+        MatchPattern::Wildcard => None,
+    }
+}
+
+/* CHECKER */
+
+/// Variables in scope map to their type, or to `None` when the type is not
+/// known statically (such as names bound by a handler for an unknown event).
+type Scope = HashMap<String, Option<VarType>>;
+
+struct TypeChecker<'a> {
+    unit: &'a InterpretationUnit,
+    names: &'a Namespace<'a>,
+    context: String,
+    span: Span,
+    scopes: Vec<Scope>,
+    returns: Option<VarType>,
+    in_actor: bool,
+    in_handler: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> TypeChecker<'a> {
+    fn new(unit: &'a InterpretationUnit, names: &'a Namespace<'a>) -> Self {
+        TypeChecker {
+            unit,
+            names,
+            context: String::new(),
+            span: Span::default(),
+            scopes: vec![],
+            returns: None,
+            in_actor: false,
+            in_handler: false,
+            diagnostics: vec![],
+        }
+    }
+
+    fn report(&mut self, kind: DiagnosticKind) {
+        let diagnostic = Diagnostic::new(&self.context, kind, &self.unit.source, self.span);
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Makes `span` the location reported for problems found until the
+    /// returned span is restored.
+    fn enter_span(&mut self, span: Span) -> Span {
+        std::mem::replace(&mut self.span, span)
+    }
+
+    fn declare(&mut self, name: &str, typ: Option<VarType>) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), typ);
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Option<VarType>> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn expect_assignable(&mut self, expected: &VarType, found: Option<VarType>) {
+        if let Some(found) = found {
+            if !is_assignable(expected, &found) {
+                self.report(DiagnosticKind::TypeMismatch { expected: expected.clone(), found });
+            }
+        }
+    }
+
+    fn expect_bool(&mut self, expr: &Spanned<ValueExpr>) {
+        if let Some(found) = self.check_expr(expr) {
+            if found != VarType::BoolType {
+                self.report(DiagnosticKind::NonBoolCondition { found });
+            }
+        }
+    }
+
+    /// The enum a type names, if it names one.
+    fn enum_of(&self, typ: &VarType) -> Option<&'a EnumSignature> {
+        let names = self.names;
+        match typ {
+            VarType::NamedType(name) => names.enums.get(name.as_str()).copied(),
+            _ => None,
+        }
+    }
+
+    /// Whether values of a type can be map keys, which have to be kept in order.
+    fn is_key(&self, typ: &VarType) -> bool {
+        matches!(typ, VarType::IntType | VarType::BoolType | VarType::StringType | VarType::ActorType)
+            || self.enum_of(typ).is_some()
+    }
+
+    /// Reports record and enum types that are not declared, and map keys of
+    /// a type that cannot be kept in order.
+    fn check_type(&mut self, typ: &VarType) {
+        match typ {
+            VarType::ListType(elem) => self.check_type(elem),
+            VarType::MapType(key, value) => {
+                if !self.is_key(key) {
+                    self.report(DiagnosticKind::InvalidKey(*key.clone()));
+                }
+                self.check_type(key);
+                self.check_type(value);
+            }
+            VarType::NamedType(name) if !self.names.records.contains_key(name.as_str()) && self.enum_of(typ).is_none() =>
+                self.report(DiagnosticKind::UnknownType(name.clone())),
+            _ => {}
+        }
+    }
+
+    /// Checks the types an event carries or a record is made of.
+    fn check_signature<'t>(&mut self, context: String, span: Span, types: impl Iterator<Item = &'t VarType>) {
+        self.context = context;
+        self.span = span;
+        for typ in types {
+            self.check_type(typ);
+        }
+    }
+
+    fn check_enum(&mut self, decl: &EnumSignature) {
+        self.context = format!("enum {}", decl.name);
+        self.span = decl.span;
+        for (i, variant) in decl.variants.iter().enumerate() {
+            if decl.variants[..i].contains(variant) {
+                self.report(DiagnosticKind::DuplicateVariant(variant.clone()));
+            }
+        }
+    }
+
+    fn check_decls(&mut self, decls: &[(String, VarType, Spanned<ValueExpr>)]) {
+        for (name, typ, initial) in decls {
+            self.check_type(typ);
+            let found = self.check_expr(initial);
+            self.expect_assignable(typ, found);
+            self.declare(name, Some(typ.clone()));
+        }
+    }
+
+    fn check_transitions(&mut self, transitions: &MultiMap<String, Transition>) {
+        for trans in in_order(transitions) {
+            let outer = self.enter_span(trans.span);
+            self.scopes.push(Scope::new());
+            let param_types = self.check_binding(trans);
+            self.in_handler = !matches!(trans.event_name.as_str(), "_ENTRY" | "_EXIT" | "_AFTER" | "_CHOICE" | "_DONE");
+            for (i, var) in trans.bound_vars.iter().enumerate() {
+                self.declare(var, param_types.get(i).cloned());
+            }
+
+            for cond in &trans.conditions {
+                self.expect_bool(cond);
+            }
+
+            self.check_block(&trans.body);
+            self.in_handler = false;
+            self.scopes.pop();
+            self.span = outer;
+        }
+    }
+
+    /// Checks an `on Event(x, y)` handler against the declared event and
+    /// returns the parameter types its bound variables take on.
+    fn check_binding(&mut self, trans: &Transition) -> Vec<VarType> {
+        if matches!(trans.event_name.as_str(), "_ENTRY" | "_EXIT" | "_AFTER" | "_CHOICE" | "_DONE") {
+            return vec![];
+        }
+
+        let names = self.names;
+        let Some(event) = names.events.get(trans.event_name.as_str()) else {
+            self.report(DiagnosticKind::UnknownEvent(trans.event_name.clone()));
+            return vec![];
+        };
+
+        if event.params.len() != trans.bound_vars.len() {
+            self.report(DiagnosticKind::EventArgumentCount {
+                event: event.name.clone(),
+                expected: event.params.len(),
+                found: trans.bound_vars.len(),
+            });
+            return vec![];
+        }
+
+        event.params.clone()
+    }
+
+    /// Checks a bare name used as a value that is not a variable in scope:
+    /// it may name an actor, which then stands for its default instance.
+    fn check_actor_name(&mut self, name: &str) -> Option<VarType> {
+        let names = self.names;
+        match names.actors.get(name) {
+            Some(actor) if actor.params.is_empty() => Some(VarType::ActorType),
+            Some(_) => {
+                self.report(DiagnosticKind::NoDefaultInstance(name.to_string()));
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn check_spawn(&mut self, actor_name: &str, args: &[Spanned<ValueExpr>]) -> Option<VarType> {
+        let arg_types: Vec<_> = args.iter().map(|arg| self.check_expr(arg)).collect();
+        let names = self.names;
+        let Some(actor) = names.actors.get(actor_name) else {
+            self.report(DiagnosticKind::UnknownActor(actor_name.to_string()));
+            return Some(VarType::ActorType);
+        };
+
+        if actor.params.len() != arg_types.len() {
+            self.report(DiagnosticKind::ArgumentCount {
+                func: actor_name.to_string(),
+                expected: actor.params.len(),
+                found: arg_types.len(),
+            });
+        } else {
+            for ((_, expected), found) in actor.params.iter().zip(arg_types) {
+                self.expect_assignable(expected, found);
+            }
+        }
+
+        Some(VarType::ActorType)
+    }
+
+    fn check_send(&mut self, target: &Spanned<ValueExpr>, event_name: &str, event_args: &[Spanned<ValueExpr>]) {
+        let arg_types: Vec<_> = event_args.iter().map(|arg| self.check_expr(arg)).collect();
+        match &target.node {
+            ValueExpr::Ident(name) if self.lookup(name).is_none() && !self.names.actors.contains_key(name.as_str()) => {
+                let outer = self.enter_span(target.span);
+                self.report(DiagnosticKind::UnknownActor(name.clone()));
+                self.span = outer;
+            }
+            _ => {
+                let found = self.check_expr(target);
+                let outer = self.enter_span(target.span);
+                self.expect_assignable(&VarType::ActorType, found);
+                self.span = outer;
+            }
+        }
+
+        let names = self.names;
+
+        let Some(event) = names.events.get(event_name) else {
+            self.report(DiagnosticKind::UnknownEvent(event_name.to_string()));
+            return;
+        };
+
+        if event.params.len() != arg_types.len() {
+            self.report(DiagnosticKind::EventArgumentCount {
+                event: event_name.to_string(),
+                expected: event.params.len(),
+                found: arg_types.len(),
+            });
+        } else {
+            for (expected, found) in event.params.iter().zip(arg_types) {
+                self.expect_assignable(expected, found);
+            }
+        }
+    }
+
+    fn check_state(&mut self, path: &str, state: &State) {
+        let outer = std::mem::replace(&mut self.context, path.to_string());
+        let outer_span = self.enter_span(state.span);
+        self.scopes.push(Scope::new());
+        self.check_decls(&state.decls);
+        self.check_transitions(&state.transitions);
+
+        for (event_name, span) in &state.deferred {
+            if !self.names.events.contains_key(event_name.as_str()) {
+                let outer = self.enter_span(*span);
+                self.report(DiagnosticKind::UnknownEvent(event_name.clone()));
+                self.span = outer;
+            }
+        }
+
+        let mut subs: Vec<_> = state.subs.values().collect();
+        subs.sort_by_key(|sub| sub.id);
+        for sub in subs {
+            self.check_state(&format!("{}.{}", path, sub.name), sub);
+        }
+
+        self.scopes.pop();
+        self.span = outer_span;
+        self.context = outer;
+    }
+
+    fn check_actor(&mut self, actor: &Actor) {
+        self.context = actor.name.clone();
+        self.span = actor.span;
+        self.in_actor = true;
+        self.scopes.push(Scope::new());
+        for (name, typ) in &actor.params {
+            self.check_type(typ);
+            self.declare(name, Some(typ.clone()));
+        }
+        self.check_decls(&actor.decls);
+
+        // At runtime the actor's own handlers look in the state machine's
+        // root before the actor, so its variables are in scope here too.
+        self.scopes.push(Scope::new());
+        if let Some(statemachine) = &actor.statemachine {
+            for (name, typ, _) in &statemachine.decls {
+                self.declare(name, Some(typ.clone()));
+            }
+        }
+        self.check_transitions(&actor.transitions);
+        self.scopes.pop();
+
+        if let Some(statemachine) = &actor.statemachine {
+            self.check_state(&actor.name, statemachine);
+        }
+
+        self.scopes.pop();
+        self.in_actor = false;
+    }
+
+    fn check_func(&mut self, func: &FuncSignature) {
+        self.context = format!("func {}", func.func_name);
+        self.span = func.span;
+        self.scopes.push(Scope::new());
+        for (name, typ) in &func.params {
+            self.check_type(typ);
+            self.declare(name, Some(typ.clone()));
+        }
+        if let Some(ret_type) = &func.ret_type {
+            self.check_type(ret_type);
+        }
+
+        self.returns = func.ret_type.clone();
+        self.check_block(&func.body);
+        if func.ret_type.is_some() && !always_returns(&func.body) {
+            self.report(DiagnosticKind::MissingReturn(func.func_name.clone()));
+        }
+
+        self.returns = None;
+        self.scopes.pop();
+    }
+
+    fn check_block(&mut self, block: &[Spanned<ControlFlowExpr>]) {
+        self.scopes.push(Scope::new());
+        for stmt in block {
+            self.check_stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn check_stmt(&mut self, stmt: &Spanned<ControlFlowExpr>) {
+        let outer = self.enter_span(stmt.span);
+        match &stmt.node {
+            ControlFlowExpr::VarDecl { var_name, var_type, initial } => {
+                self.check_type(var_type);
+                if let Some(initial) = initial {
+                    let found = self.check_expr(initial);
+                    self.expect_assignable(var_type, found);
+                }
+                self.declare(var_name, Some(var_type.clone()));
+            }
+
+            ControlFlowExpr::SendStatement { target, event, .. } => {
+                if let ValueExpr::FuncCallExpr { func_name, func_args } = &event.node {
+                    self.check_send(target, func_name, func_args);
+                }
+            }
+
+            ControlFlowExpr::AssignStatement { target, val_expr } => {
+                let found = self.check_expr(val_expr);
+                if let Some(expected) = self.check_place(target) {
+                    self.expect_assignable(&expected, found);
+                }
+            }
+
+            ControlFlowExpr::FuncCallStatement(call) => {
+                if let ValueExpr::FuncCallExpr { func_name, func_args } = &call.node {
+                    self.check_call(func_name, func_args);
+                }
+            }
+
+            ControlFlowExpr::ReturnStatement(val_expr) => {
+                match (self.returns.clone(), val_expr) {
+                    (Some(expected), Some(val_expr)) => {
+                        let found = self.check_expr(val_expr);
+                        self.expect_assignable(&expected, found);
+                    }
+                    (Some(expected), None) => self.report(DiagnosticKind::MissingReturnValue(expected)),
+                    (None, Some(val_expr)) => {
+                        self.check_expr(val_expr);
+                        self.report(DiagnosticKind::UnexpectedReturnValue);
+                    }
+                    (None, None) => {}
+                }
+            }
+
+            ControlFlowExpr::TerminateStatement => {
+                if !self.in_actor {
+                    self.report(DiagnosticKind::TerminateOutsideActor);
+                }
+            }
+
+            ControlFlowExpr::IfStatement { branches, otherwise } => {
+                for (condition, body) in branches {
+                    self.expect_bool(condition);
+                    self.check_block(body);
+                }
+                if let Some(otherwise) = otherwise {
+                    self.check_block(otherwise);
+                }
+            }
+
+            ControlFlowExpr::WhileStatement { condition, body } => {
+                self.expect_bool(condition);
+                self.check_block(body);
+            }
+
+            ControlFlowExpr::MatchStatement { scrutinee, arms } => {
+                let mut found = self.check_expr(scrutinee);
+                if let Some(typ) = found.clone() {
+                    if typ != VarType::IntType && typ != VarType::StringType && self.enum_of(&typ).is_none() {
+                        self.report(DiagnosticKind::InvalidMatch(typ));
+                        found = None;
+                    }
+                }
+
+                let mut seen: Vec<&MatchPattern> = vec![];
+                for (pattern, body) in arms {
+                    if seen.contains(&&MatchPattern::Wildcard) || seen.contains(&pattern) {
+                        self.report(DiagnosticKind::UnreachablePattern(pattern.to_string()));
+                    }
+                    seen.push(pattern);
+
+                    if let MatchPattern::Variant { enum_name, variant } = pattern {
+                        self.check_variant(enum_name, variant);
+                    }
+                    if let (Some(expected), Some(typ)) = (&found, pattern_type(pattern)) {
+                        if *expected != typ {
+                            self.report(DiagnosticKind::TypeMismatch { expected: expected.clone(), found: typ });
+                        }
+                    }
+                    self.check_block(body);
+                }
+
+                // A match on an enum has to cover every variant, unless it
+                // has a wildcard arm.
+                if let Some(decl) = found.as_ref().and_then(|typ| self.enum_of(typ)) {
+                    let missing: Vec<_> = decl.variants.iter()
+                        .filter(|variant| !seen.iter().any(|pattern| match pattern {
+                            MatchPattern::Variant { variant: covered, .. } => covered == *variant,
+                            _ => **pattern == MatchPattern::Wildcard,
+                        }))
+                        .map(|variant| format!("{}::{}", decl.name, variant))
+                        .collect();
+                    if !missing.is_empty() {
+                        self.report(DiagnosticKind::NonExhaustiveMatch(missing));
+                    }
+                }
+            }
+        }
+        self.span = outer;
+    }
+
+    /// Checks something assigned to, or changed by `insert` or `remove`:
+    /// a variable, possibly followed by indexes and field accesses. Returns
+    /// its type.
+    fn check_place(&mut self, place: &Spanned<ValueExpr>) -> Option<VarType> {
+        let mut root = place;
+        while let ValueExpr::IndexExpr { v, .. } | ValueExpr::FieldExpr { v, .. } = &root.node {
+            root = v;
+        }
+
+        match &root.node {
+            ValueExpr::Ident(name) if self.lookup(name).is_none() => {
+                self.report(DiagnosticKind::UnknownVariable(name.clone()));
+                None
+            }
+            ValueExpr::Ident(_) => self.check_expr(place),
+            _ => {
+                self.check_expr(place);
+                let outer = self.enter_span(place.span);
+                self.report(DiagnosticKind::NotAssignable);
+                self.span = outer;
+                None
+            }
+        }
+    }
+
+    /// Checks the items of a list or map literal against the type written
+    /// out for them, or against each other, and returns the type they share.
+    fn check_items<'e>(&mut self, given: Option<&VarType>, items: impl Iterator<Item = &'e Spanned<ValueExpr>>) -> Option<VarType> {
+        if let Some(given) = given {
+            self.check_type(given);
+        }
+
+        let mut common = given.cloned();
+        let mut known = true;
+        for item in items {
+            let Some(found) = self.check_expr(item) else {
+                known = false;
+                continue;
+            };
+
+            match &common {
+                None => common = Some(found),
+                Some(typ) if is_assignable(typ, &found) => {}
+                Some(typ) if given.is_none() && is_assignable(&found, typ) => common = Some(found),
+                Some(typ) => {
+                    let outer = self.enter_span(item.span);
+                    self.report(DiagnosticKind::TypeMismatch { expected: typ.clone(), found });
+                    self.span = outer;
+                }
+            }
+        }
+
+        if known || given.is_some() { common } else { None }
+    }
+
+    /// Checks that an enum declares a variant and returns the enum's type.
+    fn check_variant(&mut self, enum_name: &str, variant: &str) -> Option<VarType> {
+        let typ = VarType::NamedType(enum_name.to_string());
+        let Some(decl) = self.enum_of(&typ) else {
+            self.report(DiagnosticKind::UnknownType(enum_name.to_string()));
+            return None;
+        };
+
+        if !decl.variants.iter().any(|v| v == variant) {
+            self.report(DiagnosticKind::UnknownVariant { enum_name: enum_name.to_string(), variant: variant.to_string() });
+        }
+        Some(typ)
+    }
+
+    fn check_index(&mut self, v: &Spanned<ValueExpr>, index: &Spanned<ValueExpr>) -> Option<VarType> {
+        let (container, found) = (self.check_expr(v), self.check_expr(index));
+        match container? {
+            VarType::ListType(elem) => {
+                self.expect_assignable(&VarType::IntType, found);
+                Some(*elem)
+            }
+            VarType::MapType(key, value) => {
+                self.expect_assignable(&key, found);
+                Some(*value)
+            }
+            other => {
+                self.report(DiagnosticKind::NotIndexable(other));
+                None
+            }
+        }
+    }
+
+    fn check_field(&mut self, v: &Spanned<ValueExpr>, field: &str) -> Option<VarType> {
+        let typ = self.check_expr(v)?;
+        if let VarType::NamedType(name) = &typ {
+            let field_type = self.names.records.get(name.as_str()).and_then(|record| record.field(field));
+            if let Some(field_type) = field_type {
+                return Some(field_type.clone());
+            }
+        }
+
+        self.report(DiagnosticKind::UnknownField { typ, field: field.to_string() });
+        None
+    }
+
+    /// Checks a call to `len`, `insert` or `remove` and returns what it
+    /// gives back, as `check_call` does.
+    fn check_builtin(&mut self, func: &str, args: &[Spanned<ValueExpr>]) -> Option<Option<VarType>> {
+        let Some((first, rest)) = args.split_first() else {
+            let expected = if func == "len" { 1 } else { 2 };
+            self.report(DiagnosticKind::ArgumentCount { func: func.to_string(), expected, found: 0 });
+            return None;
+        };
+
+        if func == "len" {
+            let found = self.check_expr(first);
+            if !rest.is_empty() {
+                for arg in rest {
+                    self.check_expr(arg);
+                }
+                self.report(DiagnosticKind::ArgumentCount { func: func.to_string(), expected: 1, found: args.len() });
+            }
+            match found {
+                Some(VarType::ListType(_) | VarType::MapType(..) | VarType::StringType) | None => {}
+                Some(found) => self.report(DiagnosticKind::BuiltinArgument { func: func.to_string(), found }),
+            }
+            return Some(Some(VarType::IntType));
+        }
+
+        let target = self.check_place(first);
+        let arg_types: Vec<_> = rest.iter().map(|arg| self.check_expr(arg)).collect();
+        let (params, ret_type) = match (func, target?) {
+            ("insert", VarType::ListType(elem)) if rest.len() > 1 => (vec![VarType::IntType, *elem], None),
+            ("insert", VarType::ListType(elem)) => (vec![*elem], None),
+            ("insert", VarType::MapType(key, value)) => (vec![*key, *value], None),
+            ("remove", VarType::ListType(elem)) => (vec![VarType::IntType], Some(*elem)),
+            ("remove", VarType::MapType(key, value)) => (vec![*key], Some(*value)),
+            (_, found) => {
+                self.report(DiagnosticKind::BuiltinArgument { func: func.to_string(), found });
+                return None;
+            }
+        };
+
+        if params.len() != arg_types.len() {
+            self.report(DiagnosticKind::ArgumentCount { func: func.to_string(), expected: params.len() + 1, found: args.len() });
+        } else {
+            for (expected, found) in params.iter().zip(arg_types) {
+                self.expect_assignable(expected, found);
+            }
+        }
+
+        Some(ret_type)
+    }
+
+    /// Checks building a record, which takes a value for each of its fields
+    /// in the order they were declared.
+    fn check_record(&mut self, record: &RecordSignature, args: &[Spanned<ValueExpr>]) -> Option<Option<VarType>> {
+        let arg_types: Vec<_> = args.iter().map(|arg| self.check_expr(arg)).collect();
+        if record.fields.len() != arg_types.len() {
+            self.report(DiagnosticKind::ArgumentCount {
+                func: record.name.clone(),
+                expected: record.fields.len(),
+                found: arg_types.len(),
+            });
+        } else {
+            for ((_, expected), found) in record.fields.iter().zip(arg_types) {
+                self.expect_assignable(expected, found);
+            }
+        }
+
+        Some(Some(VarType::NamedType(record.name.clone())))
+    }
+
+    /// Checks a call and returns the callee's declared return type, if any.
+    /// Built-in operations and records are called the same way as funcs.
+    fn check_call(&mut self, func_name: &str, func_args: &[Spanned<ValueExpr>]) -> Option<Option<VarType>> {
+        if BUILTINS.contains(&func_name) {
+            return self.check_builtin(func_name, func_args);
+        }
+
+        let names = self.names;
+        if let Some(record) = names.records.get(func_name) {
+            return self.check_record(record, func_args);
+        }
+
+        let arg_types: Vec<_> = func_args.iter().map(|arg| self.check_expr(arg)).collect();
+        let Some(func) = names.funcs.get(func_name) else {
+            self.report(DiagnosticKind::UnknownFunction(func_name.to_string()));
+            return None;
+        };
+
+        if func.params.len() != arg_types.len() {
+            self.report(DiagnosticKind::ArgumentCount {
+                func: func_name.to_string(),
+                expected: func.params.len(),
+                found: arg_types.len(),
+            });
+        } else {
+            for ((_, expected), found) in func.params.iter().zip(arg_types) {
+                self.expect_assignable(expected, found);
+            }
+        }
+
+        Some(func.ret_type.clone())
+    }
+
+    fn check_arith(&mut self, op: &'static str, l: &Spanned<ValueExpr>, r: &Spanned<ValueExpr>) -> Option<VarType> {
+        let (left, right) = (self.check_expr(l)?, self.check_expr(r)?);
+        if op == "+" && left == VarType::StringType && right == VarType::StringType {
+            Some(VarType::StringType)
+        } else if is_numeric(&left) && is_numeric(&right) {
+            Some(promote(&left, &right))
+        } else {
+            self.report(DiagnosticKind::InvalidOperands { op, left, right });
+            None
+        }
+    }
+
+    fn check_logic(&mut self, op: &'static str, l: &Spanned<ValueExpr>, r: &Spanned<ValueExpr>) -> Option<VarType> {
+        let (left, right) = (self.check_expr(l), self.check_expr(r));
+        match (left, right) {
+            (Some(VarType::BoolType), Some(VarType::BoolType)) => {}
+            (Some(left), Some(right)) => self.report(DiagnosticKind::InvalidOperands { op, left, right }),
+            (Some(operand), None) | (None, Some(operand)) if operand != VarType::BoolType =>
+                self.report(DiagnosticKind::InvalidOperand { op, operand }),
+            _ => {}
+        }
+
+        Some(VarType::BoolType)
+    }
+
+    fn check_equality(&mut self, op: &'static str, l: &Spanned<ValueExpr>, r: &Spanned<ValueExpr>) -> Option<VarType> {
+        if let (Some(left), Some(right)) = (self.check_expr(l), self.check_expr(r)) {
+            if left != right && !(is_numeric(&left) && is_numeric(&right)) {
+                self.report(DiagnosticKind::InvalidOperands { op, left, right });
+            }
+        }
+
+        Some(VarType::BoolType)
+    }
+
+    fn check_ordering(&mut self, op: &'static str, l: &Spanned<ValueExpr>, r: &Spanned<ValueExpr>) -> Option<VarType> {
+        if let (Some(left), Some(right)) = (self.check_expr(l), self.check_expr(r)) {
+            if !(is_numeric(&left) && is_numeric(&right)) {
+                self.report(DiagnosticKind::InvalidOperands { op, left, right });
+            }
+        }
+
+        Some(VarType::BoolType)
+    }
+
+    /// Infers the type of an expression, reporting any problems found in it.
+    /// Returns `None` when the type cannot be determined; callers should not
+    /// report further errors in that case, as the cause was already reported.
+    fn check_expr(&mut self, expr: &Spanned<ValueExpr>) -> Option<VarType> {
+        let outer = self.enter_span(expr.span);
+        let typ = self.infer(&expr.node);
+        self.span = outer;
+        typ
+    }
+
+    fn infer(&mut self, expr: &ValueExpr) -> Option<VarType> {
+        match expr {
+            ValueExpr::Bool(_) => Some(VarType::BoolType),
+            ValueExpr::Int(_) => Some(VarType::IntType),
+            ValueExpr::Float(_) => Some(VarType::FloatType),
+            ValueExpr::Str(_) => Some(VarType::StringType),
+            ValueExpr::Ident(name) => match self.lookup(name).cloned() {
+                Some(typ) => typ,
+                None if self.names.actors.contains_key(name.as_str()) => self.check_actor_name(name),
+                None => {
+                    self.report(DiagnosticKind::UnknownVariable(name.clone()));
+                    None
+                }
+            },
+            ValueExpr::AddExpr { l, r } => self.check_arith("+", l, r),
+            ValueExpr::SubExpr { l, r } => self.check_arith("-", l, r),
+            ValueExpr::MulExpr { l, r } => self.check_arith("*", l, r),
+            ValueExpr::DivExpr { l, r } => self.check_arith("/", l, r),
+            ValueExpr::NotExpr { v } => {
+                if let Some(operand) = self.check_expr(v) {
+                    if operand != VarType::BoolType {
+                        self.report(DiagnosticKind::InvalidOperand { op: "!", operand });
+                    }
+                }
+                Some(VarType::BoolType)
+            }
+            ValueExpr::OrExpr { l, r } => self.check_logic("||", l, r),
+            ValueExpr::AndExpr { l, r } => self.check_logic("&&", l, r),
+            ValueExpr::XorExpr { l, r } => self.check_logic("^^", l, r),
+            ValueExpr::EqExpr { l, r } => self.check_equality("==", l, r),
+            ValueExpr::NeqExpr { l, r } => self.check_equality("!=", l, r),
+            ValueExpr::LeqExpr { l, r } => self.check_ordering("<=", l, r),
+            ValueExpr::GeqExpr { l, r } => self.check_ordering(">=", l, r),
+            ValueExpr::LtExpr { l, r } => self.check_ordering("<", l, r),
+            ValueExpr::GtExpr { l, r } => self.check_ordering(">", l, r),
+            ValueExpr::FuncCallExpr { func_name, func_args } => {
+                match self.check_call(func_name, func_args)? {
+                    Some(ret_type) => Some(ret_type),
+                    None => {
+                        self.report(DiagnosticKind::VoidValue(func_name.clone()));
+                        None
+                    }
+                }
+            }
+            ValueExpr::SpawnExpr { actor_name, args } => self.check_spawn(actor_name, args),
+            ValueExpr::SelfRef => {
+                if !self.in_actor {
+                    self.report(DiagnosticKind::SelfOutsideActor);
+                }
+                Some(VarType::ActorType)
+            }
+            ValueExpr::Sender => {
+                if !self.in_handler {
+                    self.report(DiagnosticKind::SenderOutsideHandler);
+                }
+                Some(VarType::ActorType)
+            }
+            ValueExpr::ListExpr { elem, items } => {
+                let elem = self.check_items(elem.as_ref(), items.iter())?;
+                Some(VarType::ListType(Box::new(elem)))
+            }
+            ValueExpr::MapExpr { types, entries } => {
+                let (key, value) = types.as_ref().map(|(key, value)| (Some(key), Some(value))).unwrap_or_default();
+                let key = self.check_items(key, entries.iter().map(|(key, _)| key));
+                let value = self.check_items(value, entries.iter().map(|(_, value)| value));
+                let (key, value) = (key?, value?);
+                if types.is_none() && !self.is_key(&key) {
+                    self.report(DiagnosticKind::InvalidKey(key.clone()));
+                }
+                Some(VarType::MapType(Box::new(key), Box::new(value)))
+            }
+            ValueExpr::VariantExpr { enum_name, variant } => self.check_variant(enum_name, variant),
+            ValueExpr::IndexExpr { v, index } => self.check_index(v, index),
+            ValueExpr::FieldExpr { v, field } => self.check_field(v, field),
+        }
+    }
+}
+
+/// Runs every check over a single unit and returns the problems found.
+/// Actors, events and funcs are looked up in `names`, which holds what the
+/// unit declares or imports.
+pub fn typecheck_unit<'a>(unit: &'a InterpretationUnit, names: &'a Namespace<'a>) -> Vec<Diagnostic> {
+    let mut checker = TypeChecker::new(unit, names);
+
+    let mut records: Vec<_> = unit.records.values().collect();
+    records.sort_by_key(|record| record.span.start);
+    for record in records {
+        checker.check_signature(format!("record {}", record.name), record.span, record.fields.iter().map(|(_, typ)| typ));
+    }
+
+    let mut enums: Vec<_> = unit.enums.values().collect();
+    enums.sort_by_key(|decl| decl.span.start);
+    for decl in enums {
+        checker.check_enum(decl);
+    }
+
+    let mut events: Vec<_> = unit.events.values().collect();
+    events.sort_by_key(|event| event.span.start);
+    for event in events {
+        checker.check_signature(format!("event {}", event.name), event.span, event.params.iter());
+    }
+
+    let mut actors: Vec<_> = unit.actors.values().collect();
+    actors.sort_by_key(|actor| actor.id);
+    for actor in actors {
+        checker.check_actor(actor);
+    }
+
+    let mut funcs: Vec<_> = unit.funcs.values().collect();
+    funcs.sort_by_key(|func| func.span.start);
+    for func in funcs {
+        checker.check_func(func);
+    }
+
+    checker.diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::VarType;
+    use crate::error::ProteusError;
+    use crate::testing::{compile, diagnostics};
+    use super::DiagnosticKind;
+
+    #[test]
+    fn type_errors() {
+        let found = diagnostics(r#"
+            func f(int n) {
+                string s = n;
+                bool b = 1 + "x";
+                bool c = !n;
+                n = 2.5;
+            }
+            func g() -> int {
+                int x = y;
+                h();
+                f(1, 2);
+                int v = f(1);
+            }
+        "#);
+
+        assert_eq!(found, vec![
+            DiagnosticKind::TypeMismatch { expected: VarType::StringType, found: VarType::IntType },
+            DiagnosticKind::InvalidOperands { op: "+", left: VarType::IntType, right: VarType::StringType },
+            DiagnosticKind::InvalidOperand { op: "!", operand: VarType::IntType },
+            DiagnosticKind::TypeMismatch { expected: VarType::IntType, found: VarType::FloatType },
+            DiagnosticKind::UnknownVariable("y".to_string()),
+            DiagnosticKind::UnknownFunction("h".to_string()),
+            DiagnosticKind::ArgumentCount { func: "f".to_string(), expected: 1, found: 2 },
+            DiagnosticKind::VoidValue("f".to_string()),
+            DiagnosticKind::MissingReturn("g".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn ints_are_promoted_to_floats() {
+        assert!(compile("
+            func f(int n) {
+                float x = n;
+                float y = x * 2 + n / 4;
+                bool close = (y - x >= 0.5) || (n == 1.0);
+            }
+        ").is_ok());
+    }
+
+    #[test]
+    fn returns_are_checked_against_the_declared_type() {
+        let found = diagnostics("
+            func f() -> int { return; }
+            func g() { return 1; }
+            func h() -> string { return 1; }
+            func k(int n) -> int { int x = n; }
+        ");
+
+        assert_eq!(found, vec![
+            DiagnosticKind::MissingReturnValue(VarType::IntType),
+            DiagnosticKind::UnexpectedReturnValue,
+            DiagnosticKind::TypeMismatch { expected: VarType::StringType, found: VarType::IntType },
+            DiagnosticKind::MissingReturn("k".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn conditions_and_matches() {
+        let found = diagnostics(r#"
+            func f(int n, float x) -> int {
+                if n { }
+                while "yes" { }
+                match x {
+                    _ => { }
+                }
+                match n {
+                    1 => { }
+                    "one" => { }
+                    _ => { }
+                    2 => { }
+                }
+                if n > 0 { return 1; } else { return 0; }
+            }
+        "#);
+
+        assert_eq!(found, vec![
+            DiagnosticKind::NonBoolCondition { found: VarType::IntType },
+            DiagnosticKind::NonBoolCondition { found: VarType::StringType },
+            DiagnosticKind::InvalidMatch(VarType::FloatType),
+            DiagnosticKind::TypeMismatch { expected: VarType::IntType, found: VarType::StringType },
+            DiagnosticKind::UnreachablePattern("2".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_code() {
+        let Err(ProteusError::Type(found)) = compile("func f() {\n    int x = true;\n}") else {
+            panic!("expected a type error");
+        };
+        let location = found[0].location.as_ref().unwrap();
+
+        assert_eq!((location.line, location.column, location.width), (2, 5, 13));
+    }
+
+    #[test]
+    fn actors_with_parameters_can_only_be_spawned() {
+        let found = diagnostics("
+            event Tick();
+            actor Counter(int step) { }
+            func main() {
+                actor a = spawn Counter(\"one\");
+                actor b = spawn Counter();
+                a ! Tick();
+                Counter ! Tick();
+            }
+        ");
+
+        assert_eq!(found, [
+            DiagnosticKind::TypeMismatch { expected: VarType::IntType, found: VarType::StringType },
+            DiagnosticKind::ArgumentCount { func: "Counter".to_string(), expected: 1, found: 0 },
+            DiagnosticKind::NoDefaultInstance("Counter".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn self_and_sender_need_an_actor() {
+        let found = diagnostics("
+            event Ping();
+            actor A {
+                entry { actor me = self; actor who = sender; }
+                on Ping() { sender ! Ping(); self ! Ping(); }
+            }
+            func main() { self ! Ping(); }
+        ");
+
+        assert_eq!(found, [DiagnosticKind::SenderOutsideHandler, DiagnosticKind::SelfOutsideActor]);
+    }
+
+    #[test]
+    fn field_of_a_value_that_is_not_a_record() {
+        let found = diagnostics("
+            enum Mode { Low, High }
+            record Point { int x; int y; }
+            func f(Mode m, Point p) {
+                int speed = m.speed;
+                int z = p.z;
+            }
+        ");
+
+        assert_eq!(found, vec![
+            DiagnosticKind::UnknownField { typ: VarType::NamedType("Mode".to_string()), field: "speed".to_string() },
+            DiagnosticKind::UnknownField { typ: VarType::NamedType("Point".to_string()), field: "z".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn events_are_checked_against_their_signatures() {
+        let found = diagnostics(r#"
+            event Ping(int);
+            event Named(string, int);
+            actor A {
+                on Ping(a, b) { }
+            }
+            actor B {
+                on Pong() { }
+            }
+            actor C {
+                on Named(s, n) { int x = s; }
+            }
+            func main() {
+                A ! Ping("x");
+                A ! Ping();
+                Nobody ! Ping(1);
+                A ! Pong();
+            }
+        "#);
+
+        assert_eq!(found, vec![
+            DiagnosticKind::EventArgumentCount { event: "Ping".to_string(), expected: 1, found: 2 },
+            DiagnosticKind::UnknownEvent("Pong".to_string()),
+            DiagnosticKind::TypeMismatch { expected: VarType::IntType, found: VarType::StringType },
+            DiagnosticKind::TypeMismatch { expected: VarType::IntType, found: VarType::StringType },
+            DiagnosticKind::EventArgumentCount { event: "Ping".to_string(), expected: 1, found: 0 },
+            DiagnosticKind::UnknownActor("Nobody".to_string()),
+            DiagnosticKind::UnknownEvent("Pong".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn matches_on_enums_must_cover_every_variant() {
+        let found = diagnostics("
+            enum Mode { Low, Medium, High, Low }
+            func f(Mode m) {
+                match m {
+                    Mode::Low => { }
+                    Mode::Fast => { }
+                }
+            }
+        ");
+
+        assert_eq!(found, vec![
+            DiagnosticKind::DuplicateVariant("Low".to_string()),
+            DiagnosticKind::UnknownVariant { enum_name: "Mode".to_string(), variant: "Fast".to_string() },
+            DiagnosticKind::NonExhaustiveMatch(vec!["Mode::Medium".to_string(), "Mode::High".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn handlers_and_funcs_are_reported_in_the_order_they_were_written() {
+        let program = "
+            event A(); event B(); event C(); event D(); event E();
+            actor X {
+                on E() { int e = true; }
+                on A() { int a = true; }
+                on D() { int d = true; }
+                on B() { int b = true; }
+                on C() { int c = true; }
+            }
+            func zeta() { int z = true; }
+            func alpha() { int a = true; }
+        ";
+
+        let lines: Vec<_> = (0..10)
+            .map(|_| match compile(program) {
+                Err(ProteusError::Type(found)) =>
+                    found.iter().map(|diagnostic| diagnostic.location.as_ref().unwrap().line).collect::<Vec<_>>(),
+                _ => panic!("expected type errors"),
+            })
+            .collect();
+
+        for found in lines {
+            assert_eq!(found, vec![4, 5, 6, 7, 8, 10, 11]);
+        }
+    }
+}
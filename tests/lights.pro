@@ -1,4 +1,3 @@
-
 event PowerOn();
 event PowerOff();
 
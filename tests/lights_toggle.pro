@@ -0,0 +1,22 @@
+event PowerOn();
+event PowerOff();
+
+actor Lights {
+    statemachine {
+        initial LightsOff;
+
+        state LightsOff {
+            on PowerOn() goto LightsOn;
+        };
+
+        state LightsOn {
+            on PowerOff() goto LightsOff;
+        }
+    };
+};
+
+func main() {
+    Lights ! PowerOn();
+    Lights ! PowerOff();
+    Lights ! PowerOn();
+}
\ No newline at end of file
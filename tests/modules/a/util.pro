@@ -0,0 +1,5 @@
+event Ping(int);
+
+func twice(int n) -> int {
+    return n * 2;
+}
@@ -0,0 +1,10 @@
+import "a/util.pro";
+
+actor Counter {
+    int total = 0;
+    on Ping(n) { total = total + twice(n); }
+}
+
+func main() {
+    Counter ! Ping(3);
+}
@@ -0,0 +1,2 @@
+import "a/util.pro";
+import "b/util.pro";